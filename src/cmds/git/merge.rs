@@ -1,74 +1,172 @@
+use super::run_blocking;
+use super::Cancelled;
+use crate::interrupt::Interrupt;
 use crate::options::MergeOpts;
 use crate::CommandMessage;
 use crate::CommandOutput;
+use crate::Details;
 use crate::DirRunner;
-use anyhow::Result;
+use crate::ExitState;
+use crate::Target;
+use anyhow::{bail, Result};
 use async_trait::async_trait;
-use std::path::PathBuf;
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::task;
 
 pub struct MergeRunner {
     pub opts: MergeOpts,
+    pub timeout: Option<Duration>,
+    pub interrupt: Interrupt,
 }
 
 #[async_trait]
 impl DirRunner for MergeRunner {
     async fn process(
         &self,
-        dir: PathBuf,
+        target: Target,
         _sender: UnboundedSender<CommandMessage>,
     ) -> Result<CommandOutput> {
+        let dir = target.dir;
         let dir_out = dir.clone();
         let opts = self.opts.clone();
-        let res = task::spawn_blocking(move || git_merge(opts, &dir)).await?;
+        let details = run_blocking(self.timeout, &self.interrupt, move |cancelled| {
+            git_merge(opts, &dir, cancelled)
+        })
+        .await?;
 
-        match res {
-            Ok(s) => Ok(CommandOutput {
-                dir: dir_out,
-                output: s,
-                error: String::from(""),
-            }),
-            Err(e) => Ok(CommandOutput {
-                dir: dir_out,
-                error: e.to_string(),
-                output: String::from(""),
-            }),
+        Ok(CommandOutput {
+            dir: dir_out,
+            output: vec![],
+            error: vec![],
+            status: ExitState::Success,
+            details: Some(Details::Ffmerge(details)),
+            duration: Duration::ZERO,
+        })
+    }
+}
+
+/// What `dirmux ffmerge` did for a repository
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MergeDetails {
+    /// The branch checked out, if any
+    pub branch: Option<String>,
+    /// The branch it tracks, such as `origin/main`
+    pub upstream: Option<String>,
+    pub outcome: MergeOutcome,
+    /// The commit the branch was at, when it could be fast-forwarded
+    pub from: Option<String>,
+    /// The commit it was fast-forwarded to
+    pub to: Option<String>,
+    pub files_changed: Option<usize>,
+    pub insertions: Option<usize>,
+    pub deletions: Option<usize>,
+    /// The changes file by file, as `git diff --stat` shows them, when asked to be verbose
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diffstat: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeOutcome {
+    /// HEAD isn't on a branch
+    NoBranch,
+    /// The branch doesn't track another
+    NoUpstream,
+    UpToDate,
+    FastForwarded,
+    /// Could be fast-forwarded, but this was a dry run
+    WouldFastForward,
+    /// The branches have diverged, so they need a real merge
+    Diverged,
+}
+
+impl MergeDetails {
+    /// Details with only the outcome known
+    pub fn new(outcome: MergeOutcome) -> Self {
+        MergeDetails {
+            branch: None,
+            upstream: None,
+            outcome,
+            from: None,
+            to: None,
+            files_changed: None,
+            insertions: None,
+            deletions: None,
+            diffstat: None,
         }
     }
+
+    /// Each field's name and value as text, in order, for tables and templates
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let text = |value: &Option<String>| value.clone().unwrap_or_default();
+        let count = |value: Option<usize>| value.map(|n| n.to_string()).unwrap_or_default();
+        vec![
+            ("branch", text(&self.branch)),
+            ("upstream", text(&self.upstream)),
+            ("outcome", self.outcome.to_string()),
+            ("from", text(&self.from)),
+            ("to", text(&self.to)),
+            ("files_changed", count(self.files_changed)),
+            ("insertions", count(self.insertions)),
+            ("deletions", count(self.deletions)),
+        ]
+    }
 }
 
-fn git_merge(opts: MergeOpts, dir: &PathBuf) -> Result<String> {
+impl fmt::Display for MergeOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            MergeOutcome::NoBranch => "no_branch",
+            MergeOutcome::NoUpstream => "no_upstream",
+            MergeOutcome::UpToDate => "up_to_date",
+            MergeOutcome::FastForwarded => "fast_forwarded",
+            MergeOutcome::WouldFastForward => "would_fast_forward",
+            MergeOutcome::Diverged => "diverged",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+fn git_merge(opts: MergeOpts, dir: &Path, cancelled: &Cancelled) -> Result<MergeDetails> {
     let repo = git2::Repository::open(dir)?;
     let head = repo.head()?;
     let (head_name, remote_ref) = match head.name() {
         Some(name) => (name, repo.branch_upstream_name(name)),
-        None => return Ok(String::from("")),
+        None => return Ok(MergeDetails::new(MergeOutcome::NoBranch)),
+    };
+    let mut details = MergeDetails {
+        branch: head.shorthand().map(String::from),
+        ..MergeDetails::new(MergeOutcome::NoUpstream)
     };
 
     let remote_ref = match remote_ref {
         Ok(remote_ref) => remote_ref,
-        Err(_) => return Ok(String::from("")),
+        Err(_) => return Ok(details),
     };
 
     if let Some(remote_ref) = remote_ref.as_str() {
         let remote = repo.find_reference(remote_ref)?;
+        details.upstream = remote.shorthand().map(String::from);
         let remote_annotated_commit = repo.reference_to_annotated_commit(&remote)?;
         let merge_analysis = repo.merge_analysis(&[&remote_annotated_commit])?;
         if merge_analysis.0.is_fast_forward() {
             let mut head_ref = repo.find_reference(head_name)?;
-            let mut output = String::new();
 
+            let head_tree = head_ref.peel_to_tree()?;
+            let remote_tree = remote.peel_to_tree()?;
+            let diff = repo.diff_tree_to_tree(Some(&head_tree), Some(&remote_tree), None)?;
+            let stats = diff.stats()?;
+            details.files_changed = Some(stats.files_changed());
+            details.insertions = Some(stats.insertions());
+            details.deletions = Some(stats.deletions());
+            details.from = head_ref.target().map(|id| id.to_string());
+            details.to = Some(remote_annotated_commit.id().to_string());
             if opts.verbose {
-                let head_tree = head_ref.peel_to_tree()?;
-                let remote_tree = remote.peel_to_tree()?;
-                let diff = repo.diff_tree_to_tree(Some(&head_tree), Some(&remote_tree), None)?;
-                let diff_stats = diff.stats()?.to_buf(git2::DiffStatsFormat::FULL, 80);
-
-                if let Some(diffoutput) = diff_stats?.as_str() {
-                    output.push('\n');
-                    output.push_str(diffoutput);
-                }
+                let diff_stats = stats.to_buf(git2::DiffStatsFormat::FULL, 80)?;
+                details.diffstat = diff_stats.as_str().map(String::from);
             }
 
             let reflog_msg = format!(
@@ -77,23 +175,23 @@ fn git_merge(opts: MergeOpts, dir: &PathBuf) -> Result<String> {
                 remote_annotated_commit.id()
             );
 
-            if opts.dry == false {
+            if cancelled.is_cancelled() {
+                bail!("Cancelled before fast-forwarding {}", head_name);
+            }
+
+            if opts.dry {
+                details.outcome = MergeOutcome::WouldFastForward;
+            } else {
                 head_ref.set_target(remote_annotated_commit.id(), &reflog_msg)?;
-                repo.set_head(&head_name)?;
+                repo.set_head(head_name)?;
                 repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+                details.outcome = MergeOutcome::FastForwarded;
             }
-
-            output.push_str(&reflog_msg);
-            output.push('\n');
-            Ok(output)
         } else if merge_analysis.0.is_normal() {
-            Ok(String::from("Cannot fast-forward\n"))
-        } else if merge_analysis.0.is_up_to_date() {
-            Ok(String::from(""))
+            details.outcome = MergeOutcome::Diverged;
         } else {
-            Ok(String::from(""))
+            details.outcome = MergeOutcome::UpToDate;
         }
-    } else {
-        return Ok(String::from(""));
     }
+    Ok(details)
 }
@@ -1,5 +1,67 @@
 mod merge;
 mod status;
 
-pub use merge::MergeRunner;
-pub use status::StatusRunner;
+pub use merge::{MergeDetails, MergeOutcome, MergeRunner};
+pub use status::{StatusDetails, StatusRunner};
+
+use crate::failure::{Interrupted, TimedOut};
+use crate::interrupt::{Interrupt, Level};
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task;
+use tokio::time::sleep;
+
+/// The branch checked out in `repo`, even if it has no commits yet
+pub fn current_branch(repo: &git2::Repository) -> Option<String> {
+    let head = repo.find_reference("HEAD").ok()?;
+    let target = head.symbolic_target()?;
+    target.strip_prefix("refs/heads/").map(String::from)
+}
+
+/// Set when the caller has given up waiting on some blocking git work.
+#[derive(Clone, Default)]
+pub struct Cancelled(Arc<AtomicBool>);
+
+impl Cancelled {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Run `work` on the blocking pool, abandoning it after `timeout` or on a forced interrupt.
+///
+/// Blocking work can't be interrupted, so instead it is told it has been cancelled and is expected
+/// to stop before making any further changes to the repository.
+async fn run_blocking<T, F>(timeout: Option<Duration>, interrupt: &Interrupt, work: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Cancelled) -> Result<T> + Send + 'static,
+{
+    let cancelled = Cancelled::default();
+    let flag = cancelled.clone();
+    let handle = task::spawn_blocking(move || work(&flag));
+    tokio::pin!(handle);
+    let deadline = sleep(timeout.unwrap_or(Duration::MAX));
+    tokio::pin!(deadline);
+    let mut interrupt = interrupt.clone();
+    loop {
+        tokio::select! {
+            res = &mut handle => return res?,
+            _ = &mut deadline, if timeout.is_some() => {
+                cancelled.cancel();
+                return Err(TimedOut(timeout.unwrap_or_default()).into());
+            }
+            level = interrupt.escalated() => match level {
+                Level::Running => {}
+                Level::Stopping => cancelled.cancel(),
+                Level::Killing => bail!(Interrupted),
+            },
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::dirs::{read_file, FileFormat};
+use crate::options::Options;
+use anyhow::{bail, Context, Result};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+const LEGACY_NAME: &str = ".dirmux.json";
+
+/// Work out which config file to use.
+///
+/// In order of preference this is the `--config` option, the named `--profile`, the
+/// `DIRMUX_CONFIG` and `DIRMUX_PROFILE` environment variables, then `config.json` in the XDG config
+/// directory. Flags win over the environment, so a profile can be picked even when `DIRMUX_CONFIG`
+/// is set. The first time the XDG location is used, a config at the legacy `~/.dirmux.json`
+/// location is moved there.
+pub fn locate(opts: &Options) -> Result<PathBuf> {
+    if let Some(path) = &opts.config {
+        return Ok(path.clone());
+    }
+    let profile = match &opts.profile {
+        Some(profile) => Some(profile.clone()),
+        None => {
+            if let Some(path) = std::env::var_os("DIRMUX_CONFIG").filter(|path| !path.is_empty()) {
+                return Ok(PathBuf::from(path));
+            }
+            std::env::var("DIRMUX_PROFILE")
+                .ok()
+                .filter(|profile| !profile.is_empty())
+        }
+    };
+
+    let dir = config_dir()
+        .context("Couldn't find a home directory for the config file, use --config to give one")?;
+
+    if let Some(profile) = profile {
+        if profile.is_empty() || profile.contains(['/', '\\']) || profile == ".." {
+            bail!("Invalid profile name: {}", profile);
+        }
+        return Ok(dir.join("profiles").join(format!("{}.json", profile)));
+    }
+
+    let path = dir.join("config.json");
+    if let Some(legacy) = dirs_next::home_dir().map(|home| home.join(LEGACY_NAME)) {
+        migrate(&legacy, &path)?;
+    }
+    Ok(path)
+}
+
+/// Read the config file, treating a missing file as empty.
+pub fn load(path: &Path) -> Result<FileFormat> {
+    if !path.exists() {
+        return Ok(FileFormat::blank());
+    }
+    read_file(path).with_context(|| format!("Couldn't read config file: {}", path.display()))
+}
+
+/// `$XDG_CONFIG_HOME/dirmux`, defaulting to `~/.config/dirmux`
+fn config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => dirs_next::home_dir()?.join(".config"),
+    };
+    Some(base.join("dirmux"))
+}
+
+/// Move the config from `legacy` to `path` if there's nothing at `path` yet
+fn migrate(legacy: &Path, path: &Path) -> Result<()> {
+    if path.exists() || !legacy.is_file() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Couldn't create config directory: {}", parent.display()))?;
+    }
+    match std::fs::rename(legacy, path) {
+        Ok(()) => {}
+        // Renaming fails across filesystems, so fall back to copying
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            std::fs::copy(legacy, path)?;
+            std::fs::remove_file(legacy)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+    eprintln!(
+        "Moved config file from {} to {}",
+        legacy.display(),
+        path.display()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_migrate() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-migrate-{}", std::process::id()));
+    let legacy = root.join(LEGACY_NAME);
+    let path = root.join("config").join("dirmux").join("config.json");
+    std::fs::create_dir_all(&root)?;
+    std::fs::write(&legacy, "{\"tags\":{}}")?;
+
+    migrate(&legacy, &path)?;
+    assert!(!legacy.exists());
+    assert_eq!(FileFormat::blank(), load(&path)?);
+
+    // An existing config is never overwritten
+    std::fs::write(&legacy, "{}")?;
+    migrate(&legacy, &path)?;
+    assert!(legacy.exists());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
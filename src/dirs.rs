@@ -1,82 +1,1013 @@
-use anyhow::Result;
+use crate::options::Order;
+use crate::paths::{expand, expand_str, portable};
+use crate::query::{is_within, TagExpr, SEPARATOR};
+use crate::rules::{Rule, Rules};
+use crate::Target;
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::BufReader;
+use std::fs::OpenOptions;
+use std::io::BufWriter;
+use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 
+/// The version of the config file format written by this version of dirmux
+pub const VERSION: u32 = 2;
+
 /// File format for the config file.
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+///
+/// Directories are held by their absolute paths, but written to the file in a portable form.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileFormat {
+    version: u32,
+    /// Where directories with relative paths are, such as `~/src` or `$WORKSPACE`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    /// Every known directory, in the order they were first added
+    #[serde(default)]
+    directories: IndexMap<PathBuf, Directory>,
+    /// Smart tags, applied to directories by rule rather than listed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<Rule>,
+    /// Environment variables for commands run in every directory
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    /// Environment variables for commands run in directories with each tag
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tag_env: BTreeMap<String, BTreeMap<String, String>>,
+    /// The shell to run scripts with, such as `bash` or `zsh -o pipefail`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shell: Option<String>,
+    /// Paths spelled with environment variables, kept as written
+    #[serde(skip)]
+    spellings: HashMap<PathBuf, String>,
+}
+
+impl PartialEq for FileFormat {
+    fn eq(&self, other: &Self) -> bool {
+        self.version == other.version
+            && self.root == other.root
+            && self.directories == other.directories
+            && self.rules == other.rules
+            && self.env == other.env
+            && self.tag_env == other.tag_env
+            && self.shell == other.shell
+    }
+}
+
+/// Everything known about a single directory.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Directory {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// A short name for the directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// The branch the directory is normally expected to be on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_branch: Option<String>,
+    /// Environment variables for commands run in this directory
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    /// Disabled directories keep their tags but are never selected
+    #[serde(skip_serializing_if = "is_true")]
+    pub enabled: bool,
+}
+
+impl Directory {
+    /// Combine the details of another entry for the same directory into this one
+    ///
+    /// Tags and environment variables are combined, and the directory is disabled if either entry
+    /// is, but different values for anything else are an error.
+    fn merge(&mut self, other: Directory) -> Result<()> {
+        for tag in other.tags {
+            if !self.tags.contains(&tag) {
+                self.tags.push(tag);
+            }
+        }
+        merge_detail("alias", &mut self.alias, other.alias)?;
+        merge_detail(
+            "default_branch",
+            &mut self.default_branch,
+            other.default_branch,
+        )?;
+        merge_detail("notes", &mut self.notes, other.notes)?;
+        for (name, value) in other.env {
+            match self.env.get(&name) {
+                Some(existing) if *existing != value => {
+                    bail!("Conflicting values for environment variable {}", name)
+                }
+                _ => {
+                    self.env.insert(name, value);
+                }
+            }
+        }
+        self.enabled &= other.enabled;
+        Ok(())
+    }
+}
+
+/// Fill in an optional detail from another entry, failing if both have different values
+fn merge_detail(name: &str, detail: &mut Option<String>, other: Option<String>) -> Result<()> {
+    match (detail.as_ref(), other) {
+        (Some(value), Some(other)) if *value != other => {
+            bail!("Conflicting values for {}: {} and {}", name, value, other)
+        }
+        (None, other) => *detail = other,
+        _ => {}
+    }
+    Ok(())
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Directory {
+            tags: vec![],
+            alias: None,
+            default_branch: None,
+            env: BTreeMap::new(),
+            notes: None,
+            enabled: true,
+        }
+    }
+}
+
+fn is_true(b: &bool) -> bool {
+    *b
+}
+
+/// The original format, which only held lists of directories by tag.
+#[derive(Deserialize)]
+struct LegacyFormat {
     tags: HashMap<String, Vec<PathBuf>>,
 }
 
+impl From<LegacyFormat> for FileFormat {
+    fn from(legacy: LegacyFormat) -> Self {
+        let mut file = FileFormat::blank();
+        let mut tags: Vec<_> = legacy.tags.into_iter().collect();
+        tags.sort();
+        for (tag, dirs) in tags {
+            for dir in dirs {
+                file.add(tag.clone(), &dir);
+            }
+        }
+        file
+    }
+}
+
 impl FileFormat {
     /// Produce a blank/empty file
     pub fn blank() -> Self {
         FileFormat {
-            tags: HashMap::new(),
+            version: VERSION,
+            root: None,
+            directories: IndexMap::new(),
+            rules: vec![],
+            env: BTreeMap::new(),
+            tag_env: BTreeMap::new(),
+            shell: None,
+            spellings: HashMap::new(),
+        }
+    }
+
+    /// The absolute directory that relative paths are under, if one is set
+    pub fn root(&self) -> Result<Option<PathBuf>> {
+        match &self.root {
+            Some(root) => {
+                let path = PathBuf::from(expand_str(root)?);
+                if !path.is_absolute() {
+                    bail!("The root must be an absolute path: {}", root);
+                }
+                Ok(Some(path))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Set the `root` that relative paths are under, as it should be written in the file
+    pub fn set_root(&mut self, root: Option<String>) {
+        self.root = root;
+    }
+
+    /// The shell set in the file for running scripts, if any
+    pub fn shell(&self) -> Option<&str> {
+        self.shell.as_deref()
+    }
+
+    /// Turn the paths as written in the file into absolute paths
+    fn expand_paths(&mut self) -> Result<()> {
+        let root = self.root()?;
+        for (spelling, dir) in std::mem::take(&mut self.directories) {
+            let spelling = spelling.to_string_lossy().to_string();
+            let path = expand(&spelling, root.as_deref())?;
+            if spelling.contains('$') {
+                self.spellings.insert(path.clone(), spelling);
+            }
+            // Two spellings of the same directory are combined
+            match self.directories.get_mut(&path) {
+                Some(existing) => existing
+                    .merge(dir)
+                    .with_context(|| format!("{} is listed more than once", path.display()))?,
+                None => {
+                    self.directories.insert(path, dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A copy with the paths spelled as they should be written to the file
+    fn portable(&self) -> Result<FileFormat> {
+        let root = self.root()?;
+        let mut file = self.clone();
+        file.directories = IndexMap::new();
+        for (path, dir) in &self.directories {
+            let spelling = match self.spellings.get(path) {
+                Some(spelling) => spelling.clone(),
+                None => portable(path, root.as_deref())?,
+            };
+            file.directories
+                .insert(PathBuf::from(spelling), dir.clone());
         }
+        Ok(file)
     }
 
     /// Add a `tag` for the specified `path`
-    pub fn add(&mut self, tag: String, path: &PathBuf) {
-        self.tags
-            .entry(tag)
-            .or_insert(vec![])
-            .insert(0, path.clone());
+    pub fn add(&mut self, tag: String, path: &Path) {
+        let dir = self.directories.entry(path.to_path_buf()).or_default();
+        if !dir.tags.contains(&tag) {
+            dir.tags.push(tag);
+        }
     }
 
     /// Remove the `tag` for the specified `path`
-    pub fn remove(&mut self, tag: String, path: &PathBuf) {
-        self.tags.entry(tag).or_insert(vec![]).retain(|x| path != x);
-        self.tags.retain(|_, v| v.len() > 0);
+    ///
+    /// A directory is forgotten entirely once it has no tags or other details left.
+    pub fn remove(&mut self, tag: String, path: &Path) {
+        if let Some(dir) = self.directories.get_mut(path) {
+            dir.tags.retain(|x| *x != tag);
+            self.prune();
+        }
     }
 
-    /// Retain only the tagged paths that match the provided lambda
+    /// Retain only the directories that match the provided lambda
     pub fn retain<F>(&mut self, f: F)
     where
         F: Fn(&PathBuf) -> bool,
     {
-        for (_, v) in &mut self.tags {
-            v.retain(|x| f(x));
+        self.directories.retain(|x, _| f(x));
+    }
+
+    /// Whether any directory or rule has `tag` or one of its sub-tags
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.directories
+            .values()
+            .any(|dir| dir.tags.iter().any(|t| is_within(t, tag)))
+            || self.rules.iter().any(|rule| is_within(&rule.tag, tag))
+    }
+
+    /// Move every directory from tag `old` to tag `new`, along with its sub-tags
+    ///
+    /// Fails if `new` already exists, unless `force` is given in which case the directories
+    /// previously tagged `new` lose that tag.
+    pub fn rename_tag(&mut self, old: &str, new: &str, force: bool) -> Result<()> {
+        self.check_tags(&[old], new, force)?;
+        if old != new {
+            self.move_tags(&[old], new, false);
+        }
+        Ok(())
+    }
+
+    /// Tag every directory tagged `src` with `dst` as well, along with its sub-tags
+    ///
+    /// Fails if `dst` already exists, unless `force` is given to replace it.
+    pub fn copy_tag(&mut self, src: &str, dst: &str, force: bool) -> Result<()> {
+        self.check_tags(&[src], dst, force)?;
+        if src != dst {
+            self.move_tags(&[src], dst, true);
+        }
+        Ok(())
+    }
+
+    /// Replace the `sources` tags with a single tag `into` covering all their directories
+    ///
+    /// Sub-tags of the `sources` become sub-tags of `into`. `into` may be one of the `sources`.
+    /// Otherwise this fails if it already exists, unless `force` is given to replace it.
+    pub fn merge_tags(&mut self, sources: &[&str], into: &str, force: bool) -> Result<()> {
+        self.check_tags(sources, into, force)?;
+        self.move_tags(sources, into, false);
+        Ok(())
+    }
+
+    /// Remove `tag` and its sub-tags from every directory, along with their rules and environment
+    pub fn delete_tag(&mut self, tag: &str) -> Result<()> {
+        if !self.has_tag(tag) {
+            bail!("No such tag: {}", tag);
+        }
+        for dir in self.directories.values_mut() {
+            dir.tags.retain(|t| !is_within(t, tag));
+        }
+        self.rules.retain(|rule| !is_within(&rule.tag, tag));
+        self.tag_env.retain(|t, _| !is_within(t, tag));
+        self.prune();
+        Ok(())
+    }
+
+    /// Check the `sources` exist and that `dest` can be written
+    fn check_tags(&self, sources: &[&str], dest: &str, force: bool) -> Result<()> {
+        for source in sources {
+            if !self.has_tag(source) {
+                bail!("No such tag: {}", source);
+            }
+            if *source != dest && (is_within(source, dest) || is_within(dest, source)) {
+                bail!("Can't move tag {} to {} within itself", source, dest);
+            }
+        }
+        if !force && !sources.contains(&dest) && self.has_tag(dest) {
+            bail!("Tag {} already exists, use --force to replace it", dest);
         }
-        self.tags.retain(|_, v| v.len() > 0);
+        Ok(())
+    }
+
+    /// Retag the `sources` and their sub-tags as `dest`, keeping the originals if `keep` is set
+    ///
+    /// Any existing `dest` tags are replaced, and the new tags take the place of the first source
+    /// tag on each directory. Rules and environment variables follow their tags, with the variables
+    /// of earlier sources winning where several are merged into one tag.
+    fn move_tags(&mut self, sources: &[&str], dest: &str, keep: bool) {
+        let replaced = |tag: &str| !sources.contains(&dest) && is_within(tag, dest);
+        let moved = |tag: &str| {
+            let index = sources.iter().position(|s| is_within(tag, s))?;
+            Some((index, format!("{}{}", dest, &tag[sources[index].len()..])))
+        };
+
+        for dir in self.directories.values_mut() {
+            dir.tags.retain(|t| !replaced(t));
+            let mut tags: Vec<String> = Vec::new();
+            for tag in dir.tags.drain(..) {
+                match moved(&tag) {
+                    Some((_, new)) => {
+                        if keep {
+                            tags.push(tag);
+                        }
+                        tags.push(new);
+                    }
+                    None => tags.push(tag),
+                }
+            }
+            let mut seen = HashSet::new();
+            tags.retain(|t| seen.insert(t.clone()));
+            dir.tags = tags;
+        }
+
+        let mut rules = Vec::new();
+        for rule in self.rules.drain(..).filter(|rule| !replaced(&rule.tag)) {
+            match moved(&rule.tag) {
+                Some((_, tag)) => {
+                    if keep {
+                        rules.push(rule.clone());
+                    }
+                    rules.push(Rule { tag, ..rule });
+                }
+                None => rules.push(rule),
+            }
+        }
+        self.rules = rules;
+
+        let mut moving = Vec::new();
+        for (tag, vars) in std::mem::take(&mut self.tag_env) {
+            if replaced(&tag) {
+                continue;
+            }
+            match moved(&tag) {
+                Some((index, new)) => {
+                    if keep {
+                        self.tag_env.insert(tag, vars.clone());
+                    }
+                    moving.push((index, new, vars));
+                }
+                None => {
+                    self.tag_env.insert(tag, vars);
+                }
+            }
+        }
+        moving.sort_by_key(|(index, _, _)| *index);
+        for (_, tag, vars) in moving {
+            let env = self.tag_env.entry(tag).or_default();
+            for (name, value) in vars {
+                env.entry(name).or_insert(value);
+            }
+        }
+        self.prune();
+    }
+
+    /// Forget directories with no tags or other details left
+    fn prune(&mut self) {
+        let blank = Directory::default();
+        self.directories.retain(|_, dir| *dir != blank);
+    }
+
+    /// Every known directory and its details
+    pub fn directories(&self) -> impl Iterator<Item = (&PathBuf, &Directory)> {
+        self.directories.iter()
+    }
+
+    pub fn directory(&self, path: &Path) -> Option<&Directory> {
+        self.directories.get(path)
+    }
+
+    pub fn directory_mut(&mut self, path: &Path) -> Option<&mut Directory> {
+        self.directories.get_mut(path)
+    }
+
+    /// The directories with `tag` or any of its sub-tags
+    pub fn tagged(&self, tag: &str) -> Vec<&PathBuf> {
+        self.directories
+            .iter()
+            .filter(|(_, dir)| dir.tags.iter().any(|t| is_within(t, tag)))
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// The directories for each tag
+    pub fn tags(&self) -> BTreeMap<&str, Vec<&PathBuf>> {
+        let mut tags: BTreeMap<&str, Vec<&PathBuf>> = BTreeMap::new();
+        for (path, dir) in &self.directories {
+            for tag in &dir.tags {
+                tags.entry(tag).or_default().push(path);
+            }
+        }
+        tags
     }
 }
 
+/// Read a config file, migrating it from older formats if necessary
 pub fn read_file(file: &Path) -> Result<FileFormat> {
-    let file = File::open(file)?;
-    let reader = BufReader::new(file);
+    /// Just enough of the file to tell which format it is in
+    #[derive(Deserialize)]
+    struct Version {
+        version: Option<serde_json::Value>,
+    }
 
-    Ok(serde_json::from_reader(reader)?)
+    let text = std::fs::read_to_string(file)?;
+    let version: Version = serde_json::from_str(&text)?;
+    match version.version.map(|v| v.as_u64()) {
+        None => Ok(serde_json::from_str::<LegacyFormat>(&text)?.into()),
+        Some(Some(version)) if version <= VERSION as u64 => {
+            // Going straight from the text keeps the directories in the order they're written in
+            let mut file: FileFormat = serde_json::from_str(&text)?;
+            file.version = VERSION;
+            file.expand_paths()?;
+            Ok(file)
+        }
+        Some(Some(version)) => bail!(
+            "Config file version {} is newer than this dirmux supports ({})",
+            version,
+            VERSION
+        ),
+        Some(None) => bail!("Config file version isn't a number"),
+    }
 }
 
+/// How many previous versions of the config file to keep, as `<file>.1` (newest) to `<file>.N`
+const BACKUPS: usize = 3;
+
+/// Write the config file atomically, keeping backups of the previous versions.
+///
+/// The new contents are written and synced to a temporary file alongside, which is then renamed
+/// over the original so a crash can never leave a partially written file behind.
 pub fn write_file(file: &FileFormat, filename: &Path) -> Result<()> {
-    let out = File::create(filename)?;
-    serde_json::to_writer_pretty(out, file)?;
+    let parent = match filename.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(parent)?;
+
+    let temp = sibling(filename, &format!("tmp.{}", std::process::id()));
+    let written = (|| -> Result<()> {
+        let mut out = BufWriter::new(File::create(&temp)?);
+        serde_json::to_writer_pretty(&mut out, &file.portable()?)?;
+        out.write_all(b"\n")?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    })();
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e.context(format!("Couldn't write {}", temp.display())));
+    }
+
+    if filename.exists() {
+        rotate_backups(filename)?;
+    }
+    std::fs::rename(&temp, filename)?;
+    // Make sure the rename itself survives a crash
+    File::open(parent)?.sync_all()?;
     Ok(())
 }
 
-pub fn get_dirs(file: FileFormat, filters: Vec<&str>) -> Result<Vec<PathBuf>> {
-    let mut dirs = Vec::new();
+/// Shift `<file>.1` to `<file>.2` and so on, then make `<file>.1` a copy of the current file
+fn rotate_backups(filename: &Path) -> Result<()> {
+    for i in (1..BACKUPS).rev() {
+        let from = sibling(filename, &i.to_string());
+        if from.exists() {
+            std::fs::rename(&from, sibling(filename, &(i + 1).to_string()))?;
+        }
+    }
+    let newest = sibling(filename, "1");
+    if std::fs::hard_link(filename, &newest).is_err() {
+        std::fs::copy(filename, &newest)?;
+    }
+    Ok(())
+}
+
+/// `filename` with an extra `.suffix` on the end
+fn sibling(filename: &Path, suffix: &str) -> PathBuf {
+    let mut name = filename.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// An exclusive advisory lock on the config file, released when dropped.
+pub struct FileLock {
+    _file: File,
+}
+
+/// Lock the config file against other dirmux processes for a read-modify-write.
+///
+/// The lock is taken on a separate `<file>.lock` file, as the config file itself is replaced on
+/// every write.
+pub fn lock_file(filename: &Path) -> Result<FileLock> {
+    if let Some(parent) = filename.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let lockname = sibling(filename, "lock");
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lockname)
+        .with_context(|| format!("Couldn't open lock file: {}", lockname.display()))?;
+    file.lock()
+        .with_context(|| format!("Couldn't lock: {}", lockname.display()))?;
+    Ok(FileLock { _file: file })
+}
 
-    if filters.len() == 0 {
-        for i in file.tags {
-            dirs.extend_from_slice(&i.1);
+#[test]
+fn test_write_backups() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-write-{}", std::process::id()));
+    let filename = root.join("config.json");
+    let _lock = lock_file(&filename)?;
+
+    let mut file = FileFormat::blank();
+    for i in 0..(BACKUPS + 2) {
+        file.add(format!("tag{}", i), Path::new("/dev/null"));
+        write_file(&file, &filename)?;
+    }
+    assert_eq!(file, read_file(&filename)?);
+
+    let mut previous = file.clone();
+    previous.remove(format!("tag{}", BACKUPS + 1), Path::new("/dev/null"));
+    assert_eq!(previous, read_file(&sibling(&filename, "1"))?);
+    assert!(sibling(&filename, &BACKUPS.to_string()).exists());
+    assert!(!sibling(&filename, &(BACKUPS + 1).to_string()).exists());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+/// The directories selected by any of the `filters`, or every enabled directory if there are none
+///
+/// As well as their listed tags, directories have the tags of any rules they meet, and directories
+/// found by the rules' paths are included alongside those in the file.
+pub fn get_dirs(file: FileFormat, filters: &[TagExpr]) -> Result<Vec<PathBuf>> {
+    let wanted: HashSet<&str> = filters.iter().flat_map(TagExpr::tags).collect();
+    // Rules can walk the filesystem and open repositories, so only those for selected tags are
+    // evaluated, and with no filter only the listed directories are selected
+    let selected: Vec<Rule> = file
+        .rules
+        .iter()
+        .filter(|rule| wanted.iter().any(|w| is_within(&rule.tag, w)))
+        .cloned()
+        .collect();
+    let rules = Rules::new(&selected, file.root()?.as_deref())?;
+    let mut directories = file.directories;
+    for path in rules.discover() {
+        directories.entry(path).or_default();
+    }
+
+    let mut dirs: Vec<PathBuf> = directories
+        .into_iter()
+        .filter(|(_, dir)| dir.enabled)
+        .filter(|(path, dir)| {
+            if filters.is_empty() {
+                return true;
+            }
+            let mut tags: HashSet<&str> = dir.tags.iter().map(String::as_str).collect();
+            tags.extend(rules.tags(path));
+            filters.iter().any(|f| f.matches(&tags))
+        })
+        .map(|(path, _)| path)
+        .collect();
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// Everything about the `dirs` needed to run commands in them
+///
+/// Environment variables set for a directory override those for its tags, which override those set
+/// globally. Sub-tags override their parents. Checking rules can mean opening every repository, so
+/// their tags are only added with `with_rules`, for commands which are given their tags.
+pub fn targets(file: &FileFormat, dirs: &[PathBuf], with_rules: bool) -> Result<Vec<Target>> {
+    let rules = if with_rules { &file.rules[..] } else { &[] };
+    let rules = Rules::new(rules, file.root()?.as_deref())?;
+    let mut tag_env: Vec<_> = file.tag_env.iter().collect();
+    tag_env.sort_by_key(|(tag, _)| (tag.split(SEPARATOR).count(), *tag));
+
+    let mut targets = Vec::new();
+    for (i, dir) in dirs.iter().enumerate() {
+        let details = file.directory(dir).cloned().unwrap_or_default();
+        let mut tags = details.tags;
+        for tag in rules.tags(dir) {
+            if !tags.iter().any(|t| t == tag) {
+                tags.push(tag.to_string());
+            }
         }
-    } else {
-        for i in filters {
-            match file.tags.get(i) {
-                Some(res) => dirs.extend_from_slice(res),
-                None => {}
+
+        let mut env = file.env.clone();
+        for (tag, vars) in &tag_env {
+            if tags.iter().any(|t| is_within(t, tag)) {
+                env.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
             }
         }
+        env.extend(details.env);
+
+        targets.push(Target {
+            dir: dir.clone(),
+            alias: details.alias,
+            default_branch: details.default_branch,
+            tags,
+            env,
+            index: i + 1,
+            total: dirs.len(),
+        });
     }
+    Ok(targets)
+}
 
-    dirs.sort();
-    dirs.dedup();
-    Ok(dirs)
+/// Put the `targets` in `order`, renumbering them to match
+///
+/// Directories found by rules come after those listed in the file for the config order. The
+/// targets are left as they are when results are shown as they complete.
+pub fn sort_targets(file: &FileFormat, targets: &mut [Target], order: Order) {
+    match order {
+        Order::Completion => return,
+        Order::Path => targets.sort_by(|a, b| a.dir.cmp(&b.dir)),
+        Order::Tag => targets.sort_by_cached_key(|target| {
+            let first = target.tags.iter().min().cloned();
+            (first.is_none(), first, target.dir.clone())
+        }),
+        Order::Config => targets.sort_by_cached_key(|target| {
+            let position = file.directories.get_index_of(&target.dir);
+            (position.unwrap_or(usize::MAX), target.dir.clone())
+        }),
+    }
+    for (i, target) in targets.iter_mut().enumerate() {
+        target.index = i + 1;
+    }
+}
+
+#[test]
+fn test_get_dirs() -> Result<()> {
+    let mut file = FileFormat::blank();
+    file.add(String::from("work"), Path::new("/src/payments"));
+    file.add(String::from("rust"), Path::new("/src/payments"));
+    file.add(String::from("work"), Path::new("/src/infra"));
+    file.add(String::from("archived"), Path::new("/src/infra"));
+    file.add(String::from("home"), Path::new("/src/blog"));
+
+    let all = get_dirs(file.clone(), &[])?;
+    assert_eq!(3, all.len());
+
+    let mut disabled = file.clone();
+    if let Some(dir) = disabled.directory_mut(Path::new("/src/blog")) {
+        dir.enabled = false;
+    }
+    assert_eq!(2, get_dirs(disabled, &[])?.len());
+
+    let filters = vec!["work & !archived".parse()?];
+    assert_eq!(
+        vec![PathBuf::from("/src/payments")],
+        get_dirs(file.clone(), &filters)?
+    );
+
+    let filters = vec!["rust".parse()?, "home".parse()?];
+    assert_eq!(
+        vec![PathBuf::from("/src/blog"), PathBuf::from("/src/payments")],
+        get_dirs(file.clone(), &filters)?
+    );
+
+    // Rules add tags to directories that meet them, listed or not
+    let root = std::env::temp_dir().join(format!("dirmux-get-dirs-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("tools"))?;
+    std::fs::write(root.join("tools/Cargo.toml"), "")?;
+    file.add(String::from("home"), &root.join("tools"));
+    file.rules.push(Rule {
+        tag: String::from("rust"),
+        path: None,
+        contains: vec![String::from("Cargo.toml")],
+        remote: None,
+        branch: None,
+    });
+    file.rules.push(Rule {
+        tag: String::from("scratch"),
+        path: Some(format!("{}/*", root.display())),
+        contains: vec![],
+        remote: None,
+        branch: None,
+    });
+    let filters = vec!["rust & home".parse()?];
+    assert_eq!(vec![root.join("tools")], get_dirs(file.clone(), &filters)?);
+    std::fs::create_dir_all(root.join("notes"))?;
+    let filters = vec!["scratch & !rust".parse()?];
+    assert_eq!(vec![root.join("notes")], get_dirs(file.clone(), &filters)?);
+    // Without a filter, directories found by rules alone aren't selected
+    assert_eq!(4, get_dirs(file, &[])?.len());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_targets() -> Result<()> {
+    let mut file = FileFormat::blank();
+    file.add(String::from("work/payments"), Path::new("/src/payments"));
+    file.add(String::from("home"), Path::new("/src/blog"));
+    let vars = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    file.env = vars(&[("RUST_LOG", "info"), ("NODE_ENV", "development")]);
+    file.tag_env.insert(
+        String::from("work/payments"),
+        vars(&[("KUBECONFIG", "/etc/kube/payments")]),
+    );
+    file.tag_env.insert(
+        String::from("work"),
+        vars(&[("KUBECONFIG", "/etc/kube/work"), ("RUST_LOG", "warn")]),
+    );
+    if let Some(dir) = file.directory_mut(Path::new("/src/payments")) {
+        dir.env = vars(&[("RUST_LOG", "debug")]);
+    }
+
+    let dirs = get_dirs(file.clone(), &[])?;
+    let targets = targets(&file, &dirs, true)?;
+    assert_eq!(
+        Target {
+            dir: PathBuf::from("/src/payments"),
+            alias: None,
+            default_branch: None,
+            tags: vec![String::from("work/payments")],
+            env: vars(&[
+                ("KUBECONFIG", "/etc/kube/payments"),
+                ("NODE_ENV", "development"),
+                ("RUST_LOG", "debug"),
+            ]),
+            index: 2,
+            total: 2,
+        },
+        targets[1]
+    );
+    assert_eq!(file.env, targets[0].env);
+
+    // Rules are only checked when asked for
+    file.rules.push(Rule {
+        tag: String::from("work"),
+        path: Some(String::from("/src/blog")),
+        contains: vec![],
+        remote: None,
+        branch: None,
+    });
+    let targets = self::targets(&file, &dirs, true)?;
+    assert_eq!(vec!["home", "work"], targets[0].tags);
+    assert_eq!(
+        Some("warn"),
+        targets[0].env.get("RUST_LOG").map(String::as_str)
+    );
+    let targets = self::targets(&file, &dirs, false)?;
+    assert_eq!(vec!["home"], targets[0].tags);
+    Ok(())
+}
+
+#[test]
+fn test_rename_tag_details() -> Result<()> {
+    let mut file = FileFormat::blank();
+    file.add(String::from("work"), Path::new("/src/infra"));
+    let rule = |tag: &str| Rule {
+        tag: tag.to_string(),
+        path: Some(String::from("/src/*")),
+        contains: vec![],
+        remote: None,
+        branch: None,
+    };
+    file.rules = vec![rule("work/payments"), rule("home")];
+    let vars = |value: &str| -> BTreeMap<String, String> {
+        std::iter::once((String::from("KUBECONFIG"), value.to_string())).collect()
+    };
+    file.tag_env.insert(String::from("work"), vars("work"));
+    file.tag_env
+        .insert(String::from("work/payments"), vars("payments"));
+    let tags = |file: &FileFormat| -> (Vec<String>, Vec<String>) {
+        let rules = file.rules.iter().map(|rule| rule.tag.clone()).collect();
+        (rules, file.tag_env.keys().cloned().collect())
+    };
+
+    file.rename_tag("work", "job", false)?;
+    assert_eq!(
+        (
+            vec![String::from("job/payments"), String::from("home")],
+            vec![String::from("job"), String::from("job/payments")]
+        ),
+        tags(&file)
+    );
+    assert_eq!(Some(&vars("payments")), file.tag_env.get("job/payments"));
+
+    file.copy_tag("job", "work", false)?;
+    assert_eq!(
+        vec!["job/payments", "work/payments", "home"],
+        file.rules.iter().map(|rule| &rule.tag).collect::<Vec<_>>()
+    );
+    assert_eq!(file.tag_env.get("job"), file.tag_env.get("work"));
+
+    // Merged tags take the environment of the first source
+    file.tag_env.insert(String::from("home"), vars("home"));
+    file.merge_tags(&["home", "work"], "job", true)?;
+    assert_eq!(Some(&vars("home")), file.tag_env.get("job"));
+    assert_eq!(Some(&vars("payments")), file.tag_env.get("job/payments"));
+    assert_eq!(
+        vec!["job/payments", "job"],
+        file.rules.iter().map(|rule| &rule.tag).collect::<Vec<_>>()
+    );
+
+    file.delete_tag("job/payments")?;
+    assert_eq!(
+        (vec![String::from("job")], vec![String::from("job")]),
+        tags(&file)
+    );
+    // A tag given only by a rule can still be deleted
+    file.retain(|_| false);
+    file.delete_tag("job")?;
+    assert_eq!((vec![], vec![]), tags(&file));
+    Ok(())
+}
+
+#[test]
+fn test_sort_targets() -> Result<()> {
+    let mut file = FileFormat::blank();
+    file.add(String::from("work"), Path::new("/src/c"));
+    file.add(String::from("home"), Path::new("/src/a"));
+    file.add(String::from("archive"), Path::new("/src/b"));
+    file.add(String::from("home"), Path::new("/src/b"));
+    // The order must survive a trip through the file
+    let root = std::env::temp_dir().join(format!("dirmux-sort-{}", std::process::id()));
+    std::fs::create_dir_all(&root)?;
+    write_file(&file, &root.join("config.json"))?;
+    let file = read_file(&root.join("config.json"))?;
+    std::fs::remove_dir_all(&root)?;
+    let mut targets = targets(&file, &get_dirs(file.clone(), &[])?, false)?;
+    let order = |targets: &[Target]| -> Vec<String> {
+        targets
+            .iter()
+            .map(|t| format!("{}{}", t.index, t.dir.display()))
+            .collect()
+    };
+
+    sort_targets(&file, &mut targets, Order::Config);
+    assert_eq!(vec!["1/src/c", "2/src/a", "3/src/b"], order(&targets));
+    sort_targets(&file, &mut targets, Order::Tag);
+    assert_eq!(vec!["1/src/b", "2/src/a", "3/src/c"], order(&targets));
+    sort_targets(&file, &mut targets, Order::Completion);
+    assert_eq!(vec!["1/src/b", "2/src/a", "3/src/c"], order(&targets));
+    sort_targets(&file, &mut targets, Order::Path);
+    assert_eq!(vec!["1/src/a", "2/src/b", "3/src/c"], order(&targets));
+    Ok(())
+}
+
+#[test]
+fn test_portable_paths() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-portable-{}", std::process::id()));
+    let filename = root.join("config.json");
+    std::fs::create_dir_all(&root)?;
+    std::fs::write(
+        &filename,
+        r#"{"version": 2, "root": "/src", "directories": {
+            "payments": {"tags": ["work"]},
+            "/src/infra": {"tags": ["work"]},
+            "$CARGO_MANIFEST_DIR/home": {"tags": ["home"]}
+        }}"#,
+    )?;
+
+    let mut file = read_file(&filename)?;
+    assert_eq!(Some(PathBuf::from("/src")), file.root()?);
+    assert!(file.directory(Path::new("/src/payments")).is_some());
+    // Cargo sets this for the tests as they run, so the process environment needn't be changed
+    let blogs = Path::new(env!("CARGO_MANIFEST_DIR")).join("home");
+    assert!(file.directory(&blogs).is_some());
+
+    file.add(String::from("work"), Path::new("/src/api"));
+    file.add(String::from("work"), Path::new("/src/$cash"));
+    write_file(&file, &filename)?;
+    let written: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&filename)?)?;
+    let spellings: Vec<&String> = written["directories"]
+        .as_object()
+        .map(|dirs| dirs.keys().collect())
+        .unwrap_or_default();
+    assert_eq!(
+        vec![
+            "$$cash",
+            "$CARGO_MANIFEST_DIR/home",
+            "api",
+            "infra",
+            "payments"
+        ],
+        spellings
+    );
+    assert_eq!(file, read_file(&filename)?);
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_merge_spellings() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-spellings-{}", std::process::id()));
+    let filename = root.join("config.json");
+    std::fs::create_dir_all(&root)?;
+    std::fs::write(
+        &filename,
+        r#"{"version": 2, "root": "/src", "directories": {
+            "payments": {"tags": ["work"], "alias": "pay", "env": {"A": "1"}},
+            "/src/payments": {"tags": ["rust"], "notes": "Old", "env": {"B": "2"}, "enabled": false}
+        }}"#,
+    )?;
+    let file = read_file(&filename)?;
+    let dir = file.directory(Path::new("/src/payments")).unwrap();
+    assert_eq!(vec!["work", "rust"], dir.tags);
+    assert_eq!(Some("pay"), dir.alias.as_deref());
+    assert_eq!(Some("Old"), dir.notes.as_deref());
+    assert_eq!(2, dir.env.len());
+    assert!(!dir.enabled);
+
+    std::fs::write(
+        &filename,
+        r#"{"version": 2, "root": "/src", "directories": {
+            "payments": {"alias": "pay"},
+            "/src/payments": {"alias": "payments"}
+        }}"#,
+    )?;
+    assert!(read_file(&filename).is_err());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+#[test]
+fn test_read_legacy() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-legacy-{}", std::process::id()));
+    let filename = root.join("config.json");
+    std::fs::create_dir_all(&root)?;
+    std::fs::write(
+        &filename,
+        r#"{"tags": {"work": ["/src/payments", "/src/infra"], "rust": ["/src/payments"]}}"#,
+    )?;
+
+    let file = read_file(&filename)?;
+    let mut expected = FileFormat::blank();
+    expected.add(String::from("rust"), Path::new("/src/payments"));
+    expected.add(String::from("work"), Path::new("/src/payments"));
+    expected.add(String::from("work"), Path::new("/src/infra"));
+    assert_eq!(expected, file);
+
+    // Round trips through the current format unchanged
+    write_file(&file, &filename)?;
+    assert_eq!(file, read_file(&filename)?);
+
+    std::fs::write(&filename, r#"{"version": 99}"#)?;
+    assert!(read_file(&filename).is_err());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
 }
@@ -1,35 +1,347 @@
+use crate::cmds::git::current_branch;
+use crate::failure::{SpawnFailed, TimedOut};
+use crate::interrupt::{Interrupt, Level};
+use crate::template;
 use crate::CommandMessage;
 use crate::CommandOutput;
 use crate::DirRunner;
-use anyhow::{bail, Result};
+use crate::ExitState;
+use crate::Target;
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
-use std::path::PathBuf;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::Command;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::sleep;
 
 #[derive(Clone)]
 pub struct CommandRunner {
     pub cmd: Vec<String>,
+    /// Run the first word of `cmd` as a script with this shell, with the rest as its arguments
+    pub shell: Option<Vec<String>>,
+    /// Send each line as an increment as soon as it is read
+    pub stream: bool,
+    /// Kill the command if it runs for longer than this
+    pub timeout: Option<Duration>,
+    /// Expand placeholders such as `{dir}` in the command
+    pub template: bool,
+    /// Ctrl-C presses to forward on to the command
+    pub interrupt: Interrupt,
 }
 
 #[async_trait]
 impl DirRunner for CommandRunner {
     async fn process(
         &self,
-        dir: PathBuf,
-        _sender: UnboundedSender<CommandMessage>,
+        target: Target,
+        sender: UnboundedSender<CommandMessage>,
     ) -> Result<CommandOutput> {
-        if let Some((progname, cmd)) = self.cmd.split_first() {
-            let output = Command::new(progname).args(cmd).current_dir(&dir).output();
-            let output = output.await?;
+        let dir = target.dir.clone();
+        let cmd = self.command_line(&target);
+        if let Some((progname, cmd)) = cmd.split_first() {
+            let mut command = Command::new(progname);
+            command
+                .args(cmd)
+                .current_dir(&dir)
+                .envs(&target.env)
+                .env("DIRMUX_DIR", &dir)
+                .env("DIRMUX_TAGS", target.tags.join(" "))
+                .env("DIRMUX_INDEX", target.index.to_string())
+                .env("DIRMUX_TOTAL", target.total.to_string())
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true);
+            // A group of its own lets us kill anything the command starts along with it
+            #[cfg(unix)]
+            command.process_group(0);
+            let mut child = command.spawn().map_err(|source| SpawnFailed {
+                program: progname.clone(),
+                source,
+            })?;
+
+            let stdout = child.stdout.take().context("Output wasn't captured")?;
+            let stderr = child.stderr.take().context("Errors weren't captured")?;
+            let stream = if self.stream { Some(&sender) } else { None };
+            #[cfg(unix)]
+            let pid = child.id();
+            let finished = {
+                let run = async {
+                    tokio::try_join!(
+                        read_lines(stdout, &dir, stream, Channel::Output),
+                        read_lines(stderr, &dir, stream, Channel::Error),
+                        child.wait(),
+                    )
+                };
+                tokio::pin!(run);
+                let deadline = sleep(self.timeout.unwrap_or(Duration::MAX));
+                tokio::pin!(deadline);
+                let mut interrupt = self.interrupt.clone();
+                loop {
+                    tokio::select! {
+                        finished = &mut run => break finished.map_err(anyhow::Error::from),
+                        _ = &mut deadline, if self.timeout.is_some() => {
+                            break Err(TimedOut(self.timeout.unwrap_or_default()).into());
+                        }
+                        level = interrupt.escalated() => match level {
+                            Level::Running => {}
+                            #[cfg(unix)]
+                            Level::Stopping => signal_group(pid, libc::SIGINT),
+                            #[cfg(unix)]
+                            Level::Killing => signal_group(pid, libc::SIGKILL),
+                            // Without process groups there's nothing between asking and killing
+                            #[cfg(not(unix))]
+                            Level::Stopping => {}
+                            #[cfg(not(unix))]
+                            Level::Killing => break Err(crate::failure::Interrupted.into()),
+                        },
+                    }
+                }
+            };
+            let (output, error, status) = match finished {
+                Ok(finished) => finished,
+                Err(e) => {
+                    #[cfg(unix)]
+                    signal_group(pid, libc::SIGKILL);
+                    #[cfg(not(unix))]
+                    let _ = child.kill().await;
+                    return Err(e).with_context(|| format!("Running '{}'", progname));
+                }
+            };
 
             Ok(CommandOutput {
                 dir,
-                output: String::from_utf8(output.stdout)?,
-                error: String::from_utf8(output.stderr)?,
+                output,
+                error,
+                status: status.into(),
+                details: None,
+                duration: Duration::ZERO,
             })
         } else {
             bail!("No command provided for dir: {}", dir.display())
         }
     }
 }
+
+impl CommandRunner {
+    /// The command to run for `target`, with any placeholders expanded
+    ///
+    /// Values put into a shell script are quoted, so that they can't be taken for shell syntax.
+    fn command_line(&self, target: &Target) -> Vec<String> {
+        let expand = |arg: &String, quote: bool| {
+            if !self.template {
+                return arg.clone();
+            }
+            template::expand(arg, |name| {
+                let value = placeholder(target, name)?;
+                Some(if quote { shell_quote(&value) } else { value })
+            })
+        };
+        match (&self.shell, self.cmd.split_first()) {
+            (Some(shell), Some((script, args))) => {
+                let args: Vec<String> = args.iter().map(|arg| expand(arg, false)).collect();
+                shell_command(shell, &expand(script, true), &args)
+            }
+            _ => self.cmd.iter().map(|arg| expand(arg, false)).collect(),
+        }
+    }
+}
+
+/// The value of a `{name}` placeholder in a command run for `target`
+fn placeholder(target: &Target, name: &str) -> Option<String> {
+    let value = match name {
+        "dir" => target.dir.display().to_string(),
+        "name" => match &target.alias {
+            Some(alias) => alias.clone(),
+            None => target
+                .dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        },
+        "tag" => target.tags.first().cloned().unwrap_or_default(),
+        "tags" => target.tags.join(","),
+        "branch" => git2::Repository::open(&target.dir)
+            .ok()
+            .and_then(|repo| current_branch(&repo))
+            .unwrap_or_default(),
+        "index" => target.index.to_string(),
+        "total" => target.total.to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// The shell to run scripts with: the one from the config file, otherwise $SHELL, otherwise sh
+///
+/// The configured shell may include options, such as `bash -o pipefail`.
+pub fn shell(configured: Option<&str>) -> Vec<String> {
+    let shell = configured
+        .map(String::from)
+        .or_else(|| std::env::var("SHELL").ok())
+        .filter(|shell| !shell.trim().is_empty())
+        .unwrap_or_else(|| String::from("sh"));
+    shell.split_whitespace().map(String::from).collect()
+}
+
+/// The command running `script` with `shell`, with each of `args` quoted and added to the end
+pub fn shell_command(shell: &[String], script: &str, args: &[String]) -> Vec<String> {
+    let mut line = script.to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(&shell_quote(arg));
+    }
+    let mut cmd = shell.to_vec();
+    cmd.push(String::from("-c"));
+    cmd.push(line);
+    cmd
+}
+
+/// Quote `word` for a POSIX shell, leaving it bare if nothing in it needs quoting
+pub fn shell_quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Send `signal` to the whole process group led by `pid`
+#[cfg(unix)]
+fn signal_group(pid: Option<u32>, signal: libc::c_int) {
+    if let Some(pid) = pid {
+        // Safety: killpg has no memory safety requirements, at worst the group is already gone
+        unsafe {
+            libc::killpg(pid as libc::pid_t, signal);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Channel {
+    Output,
+    Error,
+}
+
+/// Read all of `reader`, sending each line on as an increment if `sender` is given.
+async fn read_lines<R>(
+    reader: R,
+    dir: &Path,
+    sender: Option<&UnboundedSender<CommandMessage>>,
+    channel: Channel,
+) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut all = Vec::new();
+    loop {
+        let start = all.len();
+        if reader.read_until(b'\n', &mut all).await? == 0 {
+            return Ok(all);
+        }
+        if let Some(sender) = sender {
+            let line = all[start..].to_vec();
+            let (output, error) = match channel {
+                Channel::Output => (line, vec![]),
+                Channel::Error => (vec![], line),
+            };
+            // The receiver only goes away when dirmux is exiting anyway
+            let _ = sender.send(CommandMessage::Increment(CommandOutput {
+                dir: dir.to_path_buf(),
+                output,
+                error,
+                status: ExitState::Running,
+                details: None,
+                duration: Duration::ZERO,
+            }));
+        }
+    }
+}
+
+#[test]
+fn test_command_line() {
+    let target = Target {
+        dir: std::path::PathBuf::from("/src/it's; rm x"),
+        alias: None,
+        default_branch: None,
+        tags: vec![],
+        env: Default::default(),
+        index: 1,
+        total: 1,
+    };
+    let mut runner = CommandRunner {
+        cmd: vec![String::from("cd {dir} && ls"), String::from("{name}")],
+        shell: Some(vec![String::from("sh")]),
+        stream: false,
+        timeout: None,
+        template: true,
+        interrupt: Interrupt::never(),
+    };
+    assert_eq!(
+        vec!["sh", "-c", r"cd '/src/it'\''s; rm x' && ls 'it'\''s; rm x'"],
+        runner.command_line(&target)
+    );
+    runner.shell = None;
+    assert_eq!(
+        vec!["cd /src/it's; rm x && ls", "it's; rm x"],
+        runner.command_line(&target)
+    );
+    runner.template = false;
+    assert_eq!(runner.cmd, runner.command_line(&target));
+}
+
+#[test]
+fn test_shell_command() {
+    let shell = vec![
+        String::from("bash"),
+        String::from("-o"),
+        String::from("pipefail"),
+    ];
+    assert_eq!(shell, self::shell(Some("bash  -o pipefail")));
+    let args = vec![
+        String::from("fix bug"),
+        String::from("it's"),
+        String::from("--oneline"),
+        String::new(),
+    ];
+    assert_eq!(
+        vec![
+            "sh",
+            "-c",
+            r"git log | grep 'fix bug' 'it'\''s' --oneline ''"
+        ],
+        shell_command(&[String::from("sh")], "git log | grep", &args)
+    );
+}
+
+#[tokio::test]
+async fn test_read_lines() -> std::io::Result<()> {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let input: &[u8] = b"one\ntwo\r\n\xffpartial";
+    let all = read_lines(input, Path::new("/a"), Some(&sender), Channel::Error).await?;
+    assert_eq!(input, &all[..]);
+    drop(sender);
+
+    let mut lines = Vec::new();
+    while let Some(msg) = receiver.recv().await {
+        match msg {
+            CommandMessage::Increment(msg) => {
+                assert!(msg.output.is_empty());
+                assert_eq!(Path::new("/a"), msg.dir);
+                lines.push(msg.error);
+            }
+            _ => panic!("Expected only increments"),
+        }
+    }
+    let expected: Vec<&[u8]> = vec![b"one\n", b"two\r\n", b"\xffpartial"];
+    assert_eq!(expected, lines);
+
+    let quiet = read_lines(&b"no sender"[..], Path::new("/a"), None, Channel::Output).await?;
+    assert_eq!(b"no sender", &quiet[..]);
+    Ok(())
+}
@@ -1,31 +1,101 @@
 use crate::cmds::git::*;
-use crate::exec::CommandRunner;
-use crate::options::Options;
+use crate::dirs::FileFormat;
+use crate::exec::{shell, CommandRunner};
+use crate::interrupt::Interrupt;
 use crate::options::Subcommands;
+use crate::options::{ExecOpts, ListFormat, Options, Order, OutputFormat};
 use crate::renderers::*;
 use crate::DirRunner;
 use crate::Renderer;
-use anyhow::bail;
-use anyhow::Result;
+use crate::Target;
+use anyhow::{bail, Result};
 use std::sync::Arc;
 
-pub fn create_processors(opts: Options) -> Result<(Arc<dyn DirRunner>, Arc<dyn Renderer>)> {
+pub fn create_processors(
+    opts: Options,
+    targets: &[Target],
+    file: &FileFormat,
+    interrupt: Interrupt,
+) -> Result<(Arc<dyn DirRunner>, Arc<dyn Renderer>)> {
     let processor: Arc<dyn DirRunner> = match &opts.cmd {
-        Subcommands::RawCommand(cmd) => Arc::new(CommandRunner { cmd: cmd.to_vec() }),
-        Subcommands::Exec(execcmd) => match &execcmd.cmd {
-            crate::options::ExecCmd::RawCommand(cmd) => {
-                Arc::new(CommandRunner { cmd: cmd.to_vec() })
-            }
-        },
-        Subcommands::Status(opts) => Arc::new(StatusRunner { opts: opts.clone() }),
-        Subcommands::Ffmerge(opts) => Arc::new(MergeRunner { opts: opts.clone() }),
+        Subcommands::RawCommand(cmd) => Arc::new(CommandRunner {
+            cmd: cmd.to_vec(),
+            shell: None,
+            stream: opts.stream,
+            timeout: opts.timeout,
+            template: !opts.no_template,
+            interrupt,
+        }),
+        Subcommands::Exec(execcmd) => Arc::new(CommandRunner {
+            cmd: exec_command(execcmd, file)?,
+            shell: execcmd.shell.as_ref().map(|_| shell(file.shell())),
+            stream: opts.stream,
+            timeout: execcmd.timeout.or(opts.timeout),
+            template: !(opts.no_template || execcmd.no_template),
+            interrupt,
+        }),
+        Subcommands::Status(cmdopts) => Arc::new(StatusRunner {
+            opts: cmdopts.clone(),
+            timeout: cmdopts.timeout.or(opts.timeout),
+            interrupt,
+        }),
+        Subcommands::Ffmerge(cmdopts) => Arc::new(MergeRunner {
+            opts: cmdopts.clone(),
+            timeout: cmdopts.timeout.or(opts.timeout),
+            interrupt,
+        }),
         _ => bail!("Not a supported command type for directory running processing"),
     };
 
-    let renderer: Arc<dyn Renderer> = match &opts.cmd {
-        Subcommands::Status(_) => Arc::new(NullRender::default()),
-        Subcommands::Ffmerge(_) => Arc::new(SimpleSectionRender::single_line()),
+    // The built-in commands can show their results in several ways
+    let (format, template) = match &opts.cmd {
+        Subcommands::Status(cmdopts) => (cmdopts.format, cmdopts.template.as_deref()),
+        Subcommands::Ffmerge(cmdopts) => (cmdopts.format, cmdopts.template.as_deref()),
+        _ => (ListFormat::Plain, None),
+    };
+    let renderer: Arc<dyn Renderer> = match (&opts.cmd, template) {
+        _ if opts.output != OutputFormat::Text || format == ListFormat::Json => Arc::new(
+            JsonRender::new(targets, opts.output == OutputFormat::Ndjson),
+        ),
+        (_, Some(template)) => Arc::new(TemplateRender::new(template)),
+        _ if format == ListFormat::Table => Arc::new(TableRender::default()),
+        (Subcommands::Status(_), _) => Arc::new(NullRender::new(targets)),
+        (Subcommands::Ffmerge(_), _) => Arc::new(SimpleSectionRender::single_line()),
+        _ if opts.raw => Arc::new(RawRender::new(opts.stream)),
+        _ if opts.stream => Arc::new(PrefixRender::new(targets)?),
         _ => Arc::new(SimpleSectionRender::default()),
     };
+    let renderer: Arc<dyn Renderer> = match opts.order {
+        Order::Completion => renderer,
+        _ => Arc::new(OrderedRender::new(renderer, targets)),
+    };
     Ok((processor, renderer))
 }
+
+/// The command line to run for `dirmux exec`
+///
+/// For --shell this is the script followed by its arguments, and for --script the script is run
+/// with the shell directly.
+fn exec_command(execcmd: &ExecOpts, file: &FileFormat) -> Result<Vec<String>> {
+    let args = execcmd.args.clone();
+    if let Some(script) = &execcmd.shell {
+        let mut cmd = vec![script.clone()];
+        cmd.extend(args);
+        return Ok(cmd);
+    }
+    if let Some(script) = &execcmd.script {
+        // Each command runs in its own directory, so the script must be found from here
+        let script = std::env::current_dir()?.join(script);
+        if !script.is_file() {
+            bail!("Script not found: {}", script.display());
+        }
+        let mut cmd = shell(file.shell());
+        cmd.push(script.to_string_lossy().to_string());
+        cmd.extend(args);
+        return Ok(cmd);
+    }
+    if args.is_empty() {
+        bail!("No command given to exec, nor a --shell or --script");
+    }
+    Ok(args)
+}
@@ -0,0 +1,161 @@
+use crate::renderers::cleanup_path;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// The broad category of a failure, worked out from the error chain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureKind {
+    /// The command couldn't be started, e.g. a missing binary
+    Spawn,
+    /// The command's output couldn't be decoded
+    Decode,
+    /// libgit2 reported an error
+    Git,
+    /// The directory took longer than allowed
+    Timeout,
+    /// The user stopped dirmux before the directory finished
+    Interrupted,
+    Other,
+}
+
+impl FailureKind {
+    fn classify(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if cause.is::<TimedOut>() {
+                return FailureKind::Timeout;
+            }
+            if cause.is::<Interrupted>() {
+                return FailureKind::Interrupted;
+            }
+            if cause.is::<SpawnFailed>() {
+                return FailureKind::Spawn;
+            }
+            if cause.is::<std::string::FromUtf8Error>() || cause.is::<std::str::Utf8Error>() {
+                return FailureKind::Decode;
+            }
+            if cause.is::<git2::Error>() {
+                return FailureKind::Git;
+            }
+        }
+        FailureKind::Other
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FailureKind::Spawn => "spawn failure",
+            FailureKind::Decode => "decode failure",
+            FailureKind::Git => "git error",
+            FailureKind::Timeout => "timed out",
+            FailureKind::Interrupted => "interrupted",
+            FailureKind::Other => "error",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The error for a command that couldn't be started.
+#[derive(Debug)]
+pub struct SpawnFailed {
+    pub program: String,
+    pub source: std::io::Error,
+}
+
+impl fmt::Display for SpawnFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Couldn't run '{}'", self.program)
+    }
+}
+
+impl std::error::Error for SpawnFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// The error for a directory that ran for longer than its timeout.
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gave up after {}s", self.0.as_secs_f64())
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// The error for a directory abandoned because the user interrupted dirmux.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interrupted before finishing")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// A runner error, attributed to the directory it happened in.
+#[derive(Debug)]
+pub struct DirFailure {
+    pub dir: PathBuf,
+    pub kind: FailureKind,
+    pub error: anyhow::Error,
+    /// How long the directory took before failing
+    pub duration: Duration,
+}
+
+impl DirFailure {
+    pub fn new(dir: PathBuf, error: anyhow::Error) -> Self {
+        DirFailure {
+            dir,
+            kind: FailureKind::classify(&error),
+            error,
+            duration: Duration::ZERO,
+        }
+    }
+
+    /// Record how long the directory took before failing
+    pub fn with_duration(self, duration: Duration) -> Self {
+        DirFailure { duration, ..self }
+    }
+
+    /// Each message in the error chain, outermost first
+    pub fn chain(&self) -> impl Iterator<Item = String> + '_ {
+        self.error.chain().map(|cause| cause.to_string())
+    }
+}
+
+impl fmt::Display for DirFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dir = cleanup_path(&self.dir).unwrap_or_else(|_| self.dir.display().to_string());
+        write!(f, "{}: {}: {:#}", dir, self.kind, self.error)
+    }
+}
+
+#[test]
+fn test_classify() {
+    let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+    let error = anyhow::Error::new(SpawnFailed {
+        program: String::from("nope"),
+        source: missing,
+    });
+    let failure = DirFailure::new(PathBuf::from("/dev/null"), error);
+    assert_eq!(FailureKind::Spawn, failure.kind);
+    assert_eq!(2, failure.chain().count());
+
+    // Other io errors, such as reading from a command that did start, aren't spawn failures
+    let broken = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "broken pipe");
+    let error = anyhow::Error::new(broken).context("Running 'cat'");
+    assert_eq!(FailureKind::Other, FailureKind::classify(&error));
+
+    let error = anyhow::Error::new(TimedOut(Duration::from_secs(3))).context("Running 'sleep'");
+    assert_eq!(FailureKind::Timeout, FailureKind::classify(&error));
+
+    let error = anyhow::anyhow!("something else");
+    assert_eq!(FailureKind::Other, FailureKind::classify(&error));
+}
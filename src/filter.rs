@@ -0,0 +1,110 @@
+use crate::options::Options;
+use crate::paths::expand_home;
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Narrows the directories selected by tag down by their paths.
+#[derive(Default)]
+pub struct PathFilter {
+    include: Vec<Matcher>,
+    exclude: Vec<Matcher>,
+    under: Option<PathBuf>,
+}
+
+enum Matcher {
+    /// Matches the path or any of its ancestors
+    Glob(GlobMatcher),
+    /// Matches anywhere in the path
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn glob(pattern: &str) -> Result<Self> {
+        let pattern = expand_home(pattern);
+        let glob = Glob::new(&pattern).with_context(|| format!("Invalid glob: {}", pattern))?;
+        Ok(Matcher::Glob(glob.compile_matcher()))
+    }
+
+    fn regex(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).with_context(|| format!("Invalid regex: {}", pattern))?;
+        Ok(Matcher::Regex(regex))
+    }
+
+    fn matches(&self, dir: &Path) -> bool {
+        match self {
+            Matcher::Glob(glob) => dir.ancestors().any(|d| glob.is_match(d)),
+            Matcher::Regex(regex) => regex.is_match(&dir.to_string_lossy()),
+        }
+    }
+}
+
+impl PathFilter {
+    pub fn from_options(opts: &Options) -> Result<Self> {
+        let mut filter = PathFilter::default();
+        for pattern in &opts.include {
+            filter.include.push(Matcher::glob(pattern)?);
+        }
+        for pattern in &opts.include_regex {
+            filter.include.push(Matcher::regex(pattern)?);
+        }
+        for pattern in &opts.exclude {
+            filter.exclude.push(Matcher::glob(pattern)?);
+        }
+        for pattern in &opts.exclude_regex {
+            filter.exclude.push(Matcher::regex(pattern)?);
+        }
+        if opts.here {
+            filter.under = Some(std::env::current_dir()?);
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, dir: &Path) -> bool {
+        if let Some(under) = &self.under {
+            if !dir.starts_with(under) {
+                return false;
+            }
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|m| m.matches(dir)) {
+            return false;
+        }
+        !self.exclude.iter().any(|m| m.matches(dir))
+    }
+
+    /// Retain only the directories which pass the filter
+    pub fn apply(&self, mut dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+        dirs.retain(|dir| self.matches(dir));
+        dirs
+    }
+}
+
+#[test]
+fn test_filter() -> Result<()> {
+    let dirs = vec![
+        PathBuf::from("/work/payments/api"),
+        PathBuf::from("/work/payments/vendor/lib"),
+        PathBuf::from("/work/infra"),
+        PathBuf::from("/home/blog"),
+    ];
+
+    let filter = PathFilter {
+        include: vec![Matcher::glob("/work/payments")?],
+        exclude: vec![Matcher::glob("*/vendor/*")?],
+        under: None,
+    };
+    assert_eq!(
+        vec![PathBuf::from("/work/payments/api")],
+        filter.apply(dirs.clone())
+    );
+
+    let filter = PathFilter {
+        include: vec![],
+        exclude: vec![Matcher::regex("pay|blog")?],
+        under: Some(PathBuf::from("/work")),
+    };
+    assert_eq!(vec![PathBuf::from("/work/infra")], filter.apply(dirs));
+    Ok(())
+}
@@ -0,0 +1,599 @@
+use crate::dirs::{get_dirs, FileFormat};
+use crate::exec::shell_quote;
+use crate::filter::PathFilter;
+use crate::options::{ExportOpts, ForeignFormat, ImportOpts, Options};
+use crate::paths::expand_str;
+use crate::renderers::cleanup_path;
+use crate::tag::{inspect, update};
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A directory as described by another tool
+#[derive(Debug, PartialEq)]
+struct Entry {
+    path: PathBuf,
+    tags: Vec<String>,
+    /// Where the repository is cloned from, if the tool records it
+    url: Option<String>,
+}
+
+/// The file gr keeps its tags in.
+#[derive(Deserialize)]
+struct GrConfig {
+    #[serde(default)]
+    tags: BTreeMap<String, Vec<String>>,
+}
+
+pub fn import(opts: &ImportOpts, filename: &Path) -> Result<()> {
+    let cwd = std::env::current_dir()?;
+    let source = cwd.join(&opts.file);
+    let text = std::fs::read_to_string(&source)
+        .with_context(|| format!("Couldn't read {}", source.display()))?;
+    let base = source.parent().unwrap_or(&cwd);
+    let mut entries = match opts.format {
+        ForeignFormat::Gr => parse_gr(&text, base)?,
+        ForeignFormat::Mr => parse_mr(&text, base)?,
+        ForeignFormat::Manifest => {
+            let root = match &opts.root {
+                Some(root) => cwd.join(root),
+                None => repo_root(&source).unwrap_or_else(|| cwd.clone()),
+            };
+            parse_manifest(&text, &root)?
+        }
+    };
+    // Directories are only kept while they have tags, so those from files without them get one
+    let extra = match (&opts.tag, opts.format) {
+        (Some(tag), _) => Some(tag.clone()),
+        (None, ForeignFormat::Mr) => Some(String::from("mr")),
+        (None, ForeignFormat::Manifest) => Some(String::from("manifest")),
+        (None, ForeignFormat::Gr) => None,
+    };
+    for entry in &mut entries {
+        entry.tags.extend(extra.clone());
+    }
+
+    let report = |file: &FileFormat| -> Result<String> {
+        let (mut missing, mut conflicts) = (0, 0);
+        for entry in &entries {
+            let mut states = vec![match file.directory(&entry.path) {
+                None => "new",
+                Some(dir) if entry.tags.iter().all(|t| dir.tags.contains(t)) => "unchanged",
+                Some(_) => "updated",
+            }
+            .to_string()];
+            if !entry.path.is_dir() {
+                missing += 1;
+                states.push(String::from("missing"));
+            } else if let Some(url) = &entry.url {
+                if let Some(actual) = remote_url(&entry.path).filter(|u| !same_url(u, url)) {
+                    conflicts += 1;
+                    states.push(format!("remote is {} not {}", actual, url));
+                }
+            }
+            println!("{} ({})", cleanup_path(&entry.path)?, states.join(", "));
+        }
+        Ok(format!(
+            "{} directories, {} missing, {} with a different remote\n",
+            entries.len(),
+            missing,
+            conflicts
+        ))
+    };
+
+    if opts.dry_run {
+        return inspect(filename, |file| {
+            Ok(format!("Would import {}", report(file)?))
+        });
+    }
+    update(filename, |file| {
+        let summary = report(file)?;
+        let mut f = file.clone();
+        for entry in &entries {
+            for tag in &entry.tags {
+                f.add(tag.clone(), &entry.path);
+            }
+        }
+        print!("Imported {}", summary);
+        Ok(f)
+    })
+}
+
+pub fn export(opts: &Options, exportopts: &ExportOpts, filename: &Path) -> Result<()> {
+    let file = crate::config::load(filename)?;
+    let filter = PathFilter::from_options(opts)?;
+    let dirs = filter.apply(get_dirs(file.clone(), &opts.tag)?);
+    let entries: Vec<Entry> = dirs
+        .into_iter()
+        .map(|path| Entry {
+            tags: file
+                .directory(&path)
+                .map(|dir| dir.tags.clone())
+                .unwrap_or_default(),
+            url: remote_url(&path),
+            path,
+        })
+        .collect();
+
+    let root = match &exportopts.root {
+        Some(root) => Some(std::env::current_dir()?.join(root)),
+        None => None,
+    };
+    let out = match exportopts.format {
+        ForeignFormat::Gr => write_gr(&entries)?,
+        ForeignFormat::Mr => {
+            let home = dirs_next::home_dir();
+            write_mr(&entries, root.as_deref().or(home.as_deref()))
+        }
+        ForeignFormat::Manifest => {
+            let root = match root {
+                Some(root) => root,
+                None => std::env::current_dir()?,
+            };
+            write_manifest(&entries, &root)
+        }
+    };
+    print!("{}", out);
+    Ok(())
+}
+
+/// Add an entry, combining its tags with any earlier entry for the same directory
+fn merge_entry(entries: &mut IndexMap<PathBuf, Entry>, entry: Entry) {
+    match entries.get_mut(&entry.path) {
+        Some(existing) => {
+            for tag in entry.tags {
+                if !existing.tags.contains(&tag) {
+                    existing.tags.push(tag);
+                }
+            }
+            existing.url = existing.url.take().or(entry.url);
+        }
+        None => {
+            entries.insert(entry.path.clone(), entry);
+        }
+    }
+}
+
+fn parse_gr(text: &str, base: &Path) -> Result<Vec<Entry>> {
+    let config: GrConfig = serde_json::from_str(text).context("Not a gr config file")?;
+    let mut entries = IndexMap::new();
+    for (tag, paths) in config.tags {
+        for path in paths {
+            let entry = Entry {
+                path: base.join(expand_str(&path)?),
+                // gr tags are written with an '@' on the command line, which some files keep
+                tags: vec![tag.trim_start_matches('@').to_string()],
+                url: None,
+            };
+            merge_entry(&mut entries, entry);
+        }
+    }
+    let mut entries: Vec<Entry> = entries.into_values().collect();
+    for entry in &mut entries {
+        entry.tags.sort();
+    }
+    Ok(entries)
+}
+
+fn write_gr(entries: &[Entry]) -> Result<String> {
+    let mut tags: BTreeMap<&str, Vec<&Path>> = BTreeMap::new();
+    for entry in entries {
+        for tag in &entry.tags {
+            tags.entry(tag).or_default().push(&entry.path);
+        }
+    }
+    let mut out = serde_json::to_string_pretty(&serde_json::json!({ "tags": tags }))?;
+    out.push('\n');
+    Ok(out)
+}
+
+/// Read the repositories from a `.mrconfig`, whose sections are paths relative to `base`
+fn parse_mr(text: &str, base: &Path) -> Result<Vec<Entry>> {
+    let mut entries = IndexMap::new();
+    let mut current: Option<PathBuf> = None;
+    for (number, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if let Some(section) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            current = None;
+            if section != "DEFAULT" {
+                let path = base.join(expand_str(section.trim())?);
+                merge_entry(
+                    &mut entries,
+                    Entry {
+                        path: path.clone(),
+                        tags: vec![],
+                        url: None,
+                    },
+                );
+                current = Some(path);
+            }
+            continue;
+        }
+        // Indented lines continue the previous value
+        if line.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let (key, value) = trimmed
+            .split_once('=')
+            .with_context(|| format!("Expected 'key = value' on line {}", number + 1))?;
+        if let (Some(path), "checkout") = (&current, key.trim()) {
+            if let Some(entry) = entries.get_mut(path) {
+                entry.url = clone_url(value);
+            }
+        }
+    }
+    Ok(entries.into_values().collect())
+}
+
+fn write_mr(entries: &[Entry], root: Option<&Path>) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let path = match root.and_then(|root| entry.path.strip_prefix(root).ok()) {
+            Some(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => &entry.path,
+        };
+        let _ = writeln!(out, "[{}]", path.display());
+        if let Some(url) = &entry.url {
+            let name = entry
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let _ = writeln!(
+                out,
+                "checkout = git clone {} {}",
+                shell_quote(url),
+                shell_quote(&name)
+            );
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The repository URL from a myrepos checkout command such as `git clone 'url' 'name'`
+fn clone_url(command: &str) -> Option<String> {
+    let words = shell_words(command);
+    let clone = words.iter().position(|w| w == "clone")?;
+    // Skip over options and their values, which never look like a URL or path
+    words[clone + 1..]
+        .iter()
+        .find(|w| !w.starts_with('-') && w.contains([':', '/']))
+        .cloned()
+}
+
+/// Split a simple shell command into words, removing quotes
+fn shell_words(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    for c in command.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// The directory holding the `.repo` directory that a manifest is in, if it is in one
+fn repo_root(manifest: &Path) -> Option<PathBuf> {
+    manifest
+        .ancestors()
+        .find(|dir| dir.file_name().is_some_and(|name| name == ".repo"))
+        .and_then(Path::parent)
+        .map(Path::to_path_buf)
+}
+
+/// Read the projects from a repo manifest, tagging them with their groups
+fn parse_manifest(text: &str, root: &Path) -> Result<Vec<Entry>> {
+    let doc = roxmltree::Document::parse(text).context("Not a valid manifest")?;
+    let manifest = doc.root_element();
+    if !manifest.has_tag_name("manifest") {
+        bail!(
+            "Not a manifest, the root element is <{}>",
+            manifest.tag_name().name()
+        );
+    }
+    let elements = || manifest.children().filter(|n| n.is_element());
+    let remotes: HashMap<&str, &str> = elements()
+        .filter(|n| n.has_tag_name("remote"))
+        .filter_map(|n| Some((n.attribute("name")?, n.attribute("fetch")?)))
+        .collect();
+    let default_remote = elements()
+        .find(|n| n.has_tag_name("default"))
+        .and_then(|n| n.attribute("remote"));
+
+    let mut entries = IndexMap::new();
+    for project in elements().filter(|n| n.has_tag_name("project")) {
+        let name = project
+            .attribute("name")
+            .context("A manifest project has no name")?;
+        let path = project.attribute("path").unwrap_or(name);
+        let tags = project
+            .attribute("groups")
+            .unwrap_or_default()
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|g| !g.is_empty())
+            .map(String::from)
+            .collect();
+        // Relative fetch URLs depend on where the manifest was fetched from, so can't be checked
+        let url = project
+            .attribute("remote")
+            .or(default_remote)
+            .and_then(|remote| remotes.get(remote))
+            .filter(|fetch| !fetch.starts_with('.'))
+            .map(|fetch| format!("{}/{}", fetch.trim_end_matches('/'), name));
+        merge_entry(
+            &mut entries,
+            Entry {
+                path: root.join(path),
+                tags,
+                url,
+            },
+        );
+    }
+    Ok(entries.into_values().collect())
+}
+
+fn write_manifest(entries: &[Entry], root: &Path) -> String {
+    let mut remotes: Vec<(String, String)> = Vec::new();
+    let mut projects = String::new();
+    for entry in entries {
+        let path = match entry.path.strip_prefix(root) {
+            Ok(path) if !path.as_os_str().is_empty() => path,
+            _ => {
+                eprintln!(
+                    "Skipping {}, it isn't under {}",
+                    entry.path.display(),
+                    root.display()
+                );
+                continue;
+            }
+        };
+        let (fetch, name) = match entry.url.as_deref().and_then(|url| url.rsplit_once('/')) {
+            Some(split) => split,
+            None => {
+                eprintln!("Skipping {}, it has no git remote", entry.path.display());
+                continue;
+            }
+        };
+        let remote = match remotes.iter().find(|(_, f)| f == fetch) {
+            Some((remote, _)) => remote.clone(),
+            None => {
+                let mut remote = remote_name(fetch);
+                let base = remote.clone();
+                let mut n = 1;
+                while remotes.iter().any(|(r, _)| *r == remote) {
+                    n += 1;
+                    remote = format!("{}-{}", base, n);
+                }
+                remotes.push((remote.clone(), fetch.to_string()));
+                remote
+            }
+        };
+        let _ = write!(
+            projects,
+            "  <project name=\"{}\" path=\"{}\" remote=\"{}\"",
+            xml_escape(name),
+            xml_escape(&path.to_string_lossy()),
+            xml_escape(&remote)
+        );
+        if !entry.tags.is_empty() {
+            let _ = write!(
+                projects,
+                " groups=\"{}\"",
+                xml_escape(&entry.tags.join(","))
+            );
+        }
+        projects.push_str(" />\n");
+    }
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<manifest>\n");
+    for (remote, fetch) in &remotes {
+        let _ = writeln!(
+            out,
+            "  <remote name=\"{}\" fetch=\"{}\" />",
+            xml_escape(remote),
+            xml_escape(fetch)
+        );
+    }
+    out.push_str(&projects);
+    out.push_str("</manifest>\n");
+    out
+}
+
+/// A name for a remote from the host in its URL, such as `github.com`
+fn remote_name(fetch: &str) -> String {
+    let rest = fetch.split_once("://").map_or(fetch, |(_, rest)| rest);
+    let rest = rest.split_once('@').map_or(rest, |(_, rest)| rest);
+    let host = rest.split([':', '/']).next().unwrap_or_default();
+    if host.is_empty() {
+        String::from("origin")
+    } else {
+        host.to_string()
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The URL of the directory's `origin` remote, or its first remote if it has no `origin`
+pub(crate) fn remote_url(dir: &Path) -> Option<String> {
+    let repo = git2::Repository::open(dir).ok()?;
+    let remote = match repo.find_remote("origin") {
+        Ok(remote) => remote,
+        Err(_) => {
+            let names = repo.remotes().ok()?;
+            repo.find_remote(names.get(0)?).ok()?
+        }
+    };
+    remote.url().map(String::from)
+}
+
+/// Whether two URLs point at the same repository, ignoring a trailing `/` or `.git`
+pub(crate) fn same_url(a: &str, b: &str) -> bool {
+    let normalise = |url: &str| {
+        let url = url.trim_end_matches('/');
+        url.strip_suffix(".git").unwrap_or(url).to_string()
+    };
+    normalise(a) == normalise(b)
+}
+
+#[test]
+fn test_gr() -> Result<()> {
+    let text = r#"{"tags": {"@work": ["/src/payments", "infra"], "rust": ["/src/payments"]}}"#;
+    let entries = parse_gr(text, Path::new("/src"))?;
+    assert_eq!(
+        vec![
+            Entry {
+                path: PathBuf::from("/src/payments"),
+                tags: vec![String::from("rust"), String::from("work")],
+                url: None,
+            },
+            Entry {
+                path: PathBuf::from("/src/infra"),
+                tags: vec![String::from("work")],
+                url: None,
+            },
+        ],
+        entries
+    );
+    let written = write_gr(&entries)?;
+    assert_eq!(entries, parse_gr(&written, Path::new("/"))?);
+    Ok(())
+}
+
+#[test]
+fn test_mr() -> Result<()> {
+    let text = "[DEFAULT]\nlib = echo hi\n\n# Work\n[src/payments]\n\
+                checkout = git clone --depth 1 'git@github.com:example/pay ments.git' 'payments'\n\
+                update = git pull\n  --rebase\n[/opt/tools]\n";
+    let entries = parse_mr(text, Path::new("/home/me"))?;
+    assert_eq!(
+        vec![
+            Entry {
+                path: PathBuf::from("/home/me/src/payments"),
+                tags: vec![],
+                url: Some(String::from("git@github.com:example/pay ments.git")),
+            },
+            Entry {
+                path: PathBuf::from("/opt/tools"),
+                tags: vec![],
+                url: None,
+            },
+        ],
+        entries
+    );
+    let written = write_mr(&entries, Some(Path::new("/home/me")));
+    assert!(written.starts_with("[src/payments]\n"));
+    assert_eq!(entries, parse_mr(&written, Path::new("/home/me"))?);
+    assert!(parse_mr("[a]\nnonsense\n", Path::new("/")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_manifest() -> Result<()> {
+    let text = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <manifest>
+          <remote name="aosp" fetch="https://android.googlesource.com/" />
+          <remote name="local" fetch=".." />
+          <default remote="aosp" revision="main" />
+          <project name="platform/build" path="build/make" groups="pdk,tools" />
+          <project name="platform/art" />
+          <project name="device/test" remote="local" groups="device" />
+        </manifest>"#;
+    let entries = parse_manifest(text, Path::new("/aosp"))?;
+    assert_eq!(
+        Entry {
+            path: PathBuf::from("/aosp/build/make"),
+            tags: vec![String::from("pdk"), String::from("tools")],
+            url: Some(String::from(
+                "https://android.googlesource.com/platform/build"
+            )),
+        },
+        entries[0]
+    );
+    assert_eq!(PathBuf::from("/aosp/platform/art"), entries[1].path);
+    assert_eq!(None, entries[2].url);
+
+    let written = write_manifest(&entries[..2], Path::new("/aosp"));
+    assert!(written.contains(
+        "<remote name=\"android.googlesource.com\" fetch=\"https://android.googlesource.com/platform\" />"
+    ));
+    assert_eq!(
+        &entries[..2],
+        &parse_manifest(&written, Path::new("/aosp"))?[..]
+    );
+    assert!(parse_manifest("<html />", Path::new("/")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_import_default_tag() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-import-{}", std::process::id()));
+    std::fs::create_dir_all(&root)?;
+    let source = root.join("default.xml");
+    std::fs::write(
+        &source,
+        r#"<manifest>
+          <remote name="aosp" fetch="https://android.googlesource.com/" />
+          <default remote="aosp" revision="main" />
+          <project name="platform/build" path="build/make" groups="tools" />
+          <project name="platform/art" />
+        </manifest>"#,
+    )?;
+    let filename = root.join("config.json");
+    let mut opts = ImportOpts {
+        format: ForeignFormat::Manifest,
+        file: source,
+        tag: None,
+        root: Some(root.clone()),
+        dry_run: false,
+    };
+    import(&opts, &filename)?;
+    let file = crate::dirs::read_file(&filename)?;
+    let tags = |file: &FileFormat, path: &str| {
+        file.directory(&root.join(path)).map(|dir| dir.tags.clone())
+    };
+    assert_eq!(
+        Some(vec![String::from("manifest")]),
+        tags(&file, "platform/art")
+    );
+    assert_eq!(
+        Some(vec![String::from("tools"), String::from("manifest")]),
+        tags(&file, "build/make")
+    );
+
+    // An extra tag replaces the default one
+    std::fs::remove_file(&filename)?;
+    opts.tag = Some(String::from("aosp"));
+    import(&opts, &filename)?;
+    let file = crate::dirs::read_file(&filename)?;
+    assert_eq!(
+        Some(vec![String::from("aosp")]),
+        tags(&file, "platform/art")
+    );
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// How far the user has gone in asking dirmux to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Running,
+    /// Don't start any more directories, and ask running commands to stop
+    Stopping,
+    /// Kill anything still running
+    Killing,
+}
+
+/// A handle for watching Ctrl-C presses, cheap to clone into each runner.
+#[derive(Clone)]
+pub struct Interrupt {
+    tx: Arc<watch::Sender<Level>>,
+    rx: watch::Receiver<Level>,
+}
+
+impl Interrupt {
+    /// Listen for Ctrl-C, the first press stopping the run and the second killing it
+    pub fn listen() -> Self {
+        let interrupt = Interrupt::never();
+        let handle = interrupt.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if handle.escalate() == Level::Killing {
+                    return;
+                }
+            }
+        });
+        interrupt
+    }
+
+    /// A handle that is only interrupted by calling `escalate`
+    pub fn never() -> Self {
+        let (tx, rx) = watch::channel(Level::Running);
+        Interrupt {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    /// Move on to the next level, as another Ctrl-C press would, returning the new level
+    pub fn escalate(&self) -> Level {
+        self.tx.send_modify(|level| {
+            *level = match level {
+                Level::Running => Level::Stopping,
+                _ => Level::Killing,
+            }
+        });
+        *self.tx.borrow()
+    }
+
+    pub fn level(&self) -> Level {
+        *self.rx.borrow()
+    }
+
+    /// Wait for the next escalation, returning the new level
+    pub async fn escalated(&mut self) -> Level {
+        if self.rx.changed().await.is_err() {
+            // No more escalations can happen
+            std::future::pending::<()>().await;
+        }
+        *self.rx.borrow_and_update()
+    }
+}
+
+#[tokio::test]
+async fn test_escalate() {
+    let interrupt = Interrupt::never();
+    let mut watcher = interrupt.clone();
+    assert_eq!(Level::Running, interrupt.level());
+
+    assert_eq!(Level::Stopping, interrupt.escalate());
+    assert_eq!(Level::Stopping, watcher.escalated().await);
+    assert_eq!(Level::Stopping, interrupt.level());
+
+    assert_eq!(Level::Killing, interrupt.escalate());
+    assert_eq!(Level::Killing, watcher.escalated().await);
+    // There is nothing beyond killing
+    assert_eq!(Level::Killing, interrupt.escalate());
+    assert_eq!(Level::Killing, interrupt.level());
+}
@@ -1,22 +1,53 @@
 use anyhow::Result;
 use async_trait::async_trait;
+use failure::DirFailure;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
 use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
 use tokio::sync::mpsc::UnboundedSender;
 
+/// Locating and loading the config file
+pub mod config;
 /// File handling and production of directories to work with
 pub mod dirs;
 /// Command-line execution runners
 pub mod exec;
 /// Creating different behaviours from the program options
 pub mod factory;
+/// Failures attributed to the directory they happened in
+pub mod failure;
+/// Filtering directories by their paths
+pub mod filter;
+/// Importing from and exporting to other multi-repository tools
+pub mod interop;
+/// Handling Ctrl-C while directories are running
+pub mod interrupt;
+/// Cloning and tagging the repositories listed in a workspace manifest
+pub mod manifest;
 /// Program and command line options
 pub mod options;
+/// Portable spellings of directory paths in the config file
+pub mod paths;
+/// Tag expressions for selecting directories
+pub mod query;
 /// Render the directory's outputs as text
 pub mod renderers;
+/// Smart tags defined by rules
+pub mod rules;
+/// Finding repositories to tag
+pub mod scan;
 /// Styling for commands
 pub mod styling;
+/// Tallying the results of a run into an exit code
+pub mod summary;
 /// Managing the tags
 pub mod tag;
+/// Expanding placeholders such as `{dir}` in commands
+pub mod template;
 
 pub mod cmds;
 
@@ -24,35 +55,162 @@ pub mod cmds;
 pub trait DirRunner: Send + Sync {
     async fn process(
         &self,
-        dir: PathBuf,
+        target: Target,
         sender: UnboundedSender<CommandMessage>,
     ) -> Result<CommandOutput>;
 }
 
-pub trait Renderer {
+/// A directory to work in, along with what the config file says about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub dir: PathBuf,
+    /// The short name given to the directory in the config file
+    pub alias: Option<String>,
+    /// The branch the directory is normally expected to be on
+    pub default_branch: Option<String>,
+    /// The directory's listed tags, followed by any given to it by rules
+    pub tags: Vec<String>,
+    /// Environment variables for commands, merged from the global, tag and directory settings
+    pub env: BTreeMap<String, String>,
+    /// The position of the directory in this run, counting from 1
+    pub index: usize,
+    /// How many directories are in this run
+    pub total: usize,
+}
+
+pub trait Renderer: Send + Sync {
     fn process(&self, msg: CommandMessage) -> Result<()>;
+
+    /// Called once after every directory has finished or been abandoned
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub enum CommandMessage {
+    /// Work has begun on a directory
+    Started(PathBuf),
+    /// A line of output from a directory which is still running
     Increment(CommandOutput),
     Progress(CommandProgress),
-    Final(Result<CommandOutput>),
+    Final(Result<CommandOutput, DirFailure>),
+}
+
+impl CommandMessage {
+    /// The directory the message is about
+    pub fn dir(&self) -> &Path {
+        match self {
+            CommandMessage::Started(dir) => dir,
+            CommandMessage::Increment(output) => &output.dir,
+            CommandMessage::Progress(progress) => &progress.dir,
+            CommandMessage::Final(Ok(output)) => &output.dir,
+            CommandMessage::Final(Err(failure)) => &failure.dir,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct CommandProgress {
-    progress: u32,
-    out_of: u32,
-    message: String,
-    dir: PathBuf,
+    pub progress: u32,
+    pub out_of: u32,
+    pub message: String,
+    pub dir: PathBuf,
 }
 
 #[derive(Debug)]
 pub struct CommandOutput {
-    output: String,
-    error: String,
+    /// Everything the command wrote to stdout, exactly as written
+    output: Vec<u8>,
+    /// Everything the command wrote to stderr, exactly as written
+    error: Vec<u8>,
     dir: PathBuf,
+    status: ExitState,
+    /// Structured results, for the built-in commands which have them
+    details: Option<Details>,
+    /// How long the directory took, once it has finished
+    duration: Duration,
+}
+
+impl CommandOutput {
+    /// Record how long the directory took
+    pub fn with_duration(self, duration: Duration) -> Self {
+        CommandOutput { duration, ..self }
+    }
+}
+
+/// The results of dirmux's built-in commands, for machine-readable output
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Details {
+    Status(cmds::git::StatusDetails),
+    Ffmerge(cmds::git::MergeDetails),
+}
+
+impl Details {
+    /// Each field's name and value as text, in order, for tables and templates
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Details::Status(status) => status.fields(),
+            Details::Ffmerge(merge) => merge.fields(),
+        }
+    }
+}
+
+/// How the work for a directory finished
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExitState {
+    Success,
+    /// Exited with a non-zero exit code
+    Code(i32),
+    /// Terminated by the given signal number
+    Signal(i32),
+    /// Not finished yet, as for incremental output
+    Running,
+}
+
+impl ExitState {
+    pub fn is_success(&self) -> bool {
+        *self == ExitState::Success
+    }
+
+    /// The code a shell would report for this state, signals being reported as 128 + signal.
+    pub fn code(&self) -> i32 {
+        match self {
+            ExitState::Success => 0,
+            ExitState::Code(code) => *code,
+            ExitState::Signal(signal) => 128 + signal,
+            ExitState::Running => 0,
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitState {
+    fn from(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return ExitState::Signal(signal);
+            }
+        }
+        match status.code() {
+            Some(0) => ExitState::Success,
+            Some(code) => ExitState::Code(code),
+            None => ExitState::Code(1),
+        }
+    }
+}
+
+impl fmt::Display for ExitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitState::Success => write!(f, "ok"),
+            ExitState::Code(code) => write!(f, "exit {}", code),
+            ExitState::Signal(signal) => write!(f, "signal {}", signal),
+            ExitState::Running => write!(f, "running"),
+        }
+    }
 }
 
 pub struct DebugRenderer {}
@@ -63,3 +221,15 @@ impl Renderer for DebugRenderer {
         Ok(())
     }
 }
+
+#[cfg(unix)]
+#[test]
+fn test_exit_state_from_status() {
+    use std::os::unix::process::ExitStatusExt;
+    // Raw wait statuses: the exit code is in the second byte, a signal in the low bits
+    assert_eq!(ExitState::Success, ExitStatus::from_raw(0).into());
+    assert_eq!(ExitState::Code(3), ExitStatus::from_raw(3 << 8).into());
+    assert_eq!(ExitState::Signal(9), ExitStatus::from_raw(9).into());
+    assert_eq!(137, ExitState::Signal(9).code());
+    assert_eq!(0, ExitState::Running.code());
+}
@@ -1,56 +1,94 @@
-use anyhow::Context;
 use anyhow::Result;
-use dirmux::options::Options;
+use dirmux::failure::DirFailure;
+use dirmux::filter::PathFilter;
+use dirmux::interrupt::{Interrupt, Level};
+use dirmux::options::{Options, Subcommands};
 use dirmux::styling::set_default_styles;
+use dirmux::summary::Summary;
 use dirmux::CommandMessage;
 use futures::stream::{self, StreamExt};
-use std::path::PathBuf;
+use std::io::Write;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::{sleep, Instant};
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// How long to wait after the first Ctrl-C before reporting and killing what is still running
+const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+fn main() -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let code = runtime.block_on(run());
+    // Blocking git work abandoned after a timeout can't be stopped, so don't wait for it
+    runtime.shutdown_background();
+    let code = code?;
+    if code != 0 {
+        std::io::stdout().flush()?;
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Run the command line's subcommand, returning the code to exit with
+async fn run() -> Result<i32> {
     let opts = Options::from_args();
     set_default_styles();
-    let filename = match dirs_next::home_dir() {
-        Some(homedir) => homedir.join(".dirmux.json"),
-        None => PathBuf::from("/tmp/").join(".dirmux.json"),
-    };
-    let file = match dirmux::dirs::read_file(&filename) {
-        Ok(file) => file,
-        Err(_) => {
-            let default_file = dirmux::dirs::FileFormat::blank();
-            dirmux::dirs::write_file(&default_file, &filename)
-                .with_context(|| format!("Couldn't write config file: {}", filename.display()))?;
-            dirmux::dirs::read_file(&filename)
-                .with_context(|| format!("Couldn't read config file: {}", filename.display()))?
-        }
-    };
+    let filename = dirmux::config::locate(&opts)?;
 
-    // Short circuit tag command
-    if let dirmux::options::Subcommands::Tag(tagopts) = &opts.cmd {
-        return dirmux::tag::handle(&tagopts, &filename, &file);
+    // Short circuit commands which only work on the config file
+    match &opts.cmd {
+        Subcommands::Tag(tagopts) => return dirmux::tag::handle(tagopts, &filename).map(|()| 0),
+        Subcommands::Import(importopts) => {
+            return dirmux::interop::import(importopts, &filename).map(|()| 0)
+        }
+        Subcommands::Export(exportopts) => {
+            return dirmux::interop::export(&opts, exportopts, &filename).map(|()| 0)
+        }
+        Subcommands::SyncManifest(syncopts) => {
+            return dirmux::manifest::sync_manifest(syncopts, &filename).map(|()| 0)
+        }
+        _ => {}
     }
 
-    let dirs = if let Some(t) = &opts.tag {
-        dirmux::dirs::get_dirs(file, vec![&t])?
-    } else {
-        dirmux::dirs::get_dirs(file, vec![])?
-    };
+    let file = dirmux::config::load(&filename)?;
+
+    let filter = PathFilter::from_options(&opts)?;
+    let dirs = filter.apply(dirmux::dirs::get_dirs(file.clone(), &opts.tag)?);
+    if dirs.is_empty() && !filename.exists() {
+        eprintln!(
+            "No directories are tagged in {} yet, add some with 'dirmux tag add <tag>'",
+            filename.display()
+        );
+    }
 
     let jobs = opts.jobs;
-    let (processor, renderer) = dirmux::factory::create_processors(opts)?;
+    let mut summary = Summary::new(opts.exit_code);
+    let interrupt = Interrupt::listen();
+    // Only commands run in the directories see the tags given by rules
+    let with_rules = matches!(opts.cmd, Subcommands::Exec(_) | Subcommands::RawCommand(_));
+    let mut targets = dirmux::dirs::targets(&file, &dirs, with_rules)?;
+    dirmux::dirs::sort_targets(&file, &mut targets, opts.order);
+    let (processor, renderer) =
+        dirmux::factory::create_processors(opts, &targets, &file, interrupt.clone())?;
     let (tx, mut rx) = unbounded_channel();
     let mut futs = Vec::new();
-    for dir in dirs {
+    for target in targets {
         let tx = tx.clone();
         let processor = processor.clone();
+        let interrupt = interrupt.clone();
         let fut = async move {
-            let directory = dir.clone();
-            let output = processor.process(dir, tx.clone()).await.with_context(|| {
-                format!("Processing failed for directory: {}", directory.display())
-            });
-            // TODO Handle error case by wrapping in dir variable
+            if interrupt.level() != Level::Running {
+                return;
+            }
+            tx.send(CommandMessage::Started(target.dir.clone()))
+                .unwrap();
+            let directory = target.dir.clone();
+            let started = Instant::now();
+            let output = processor
+                .process(target, tx.clone())
+                .await
+                .map(|output| output.with_duration(started.elapsed()))
+                .map_err(|e| DirFailure::new(directory, e).with_duration(started.elapsed()));
             tx.send(CommandMessage::Final(output)).unwrap();
         };
         futs.push(fut);
@@ -65,9 +103,42 @@ async fn main() -> Result<()> {
         drop(tx);
     });
 
-    while let Some(msg) = rx.recv().await {
-        renderer.process(msg)?;
+    let mut watcher = interrupt.clone();
+    let grace = sleep(Duration::MAX);
+    tokio::pin!(grace);
+    let mut reported = false;
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => {
+                    summary.record(&msg);
+                    renderer.process(msg)?;
+                }
+                None => break,
+            },
+            level = watcher.escalated() => {
+                if level == Level::Stopping {
+                    grace.as_mut().reset(Instant::now() + GRACE_PERIOD);
+                }
+            }
+            _ = &mut grace, if !reported => {
+                eprint!("{}", summary.interrupted(&dirs)?);
+                reported = true;
+                interrupt.escalate();
+            }
+        }
     }
 
-    Ok(())
+    renderer.finish()?;
+
+    let code = if interrupt.level() != Level::Running {
+        if !reported {
+            eprint!("{}", summary.interrupted(&dirs)?);
+        }
+        // Even with --exit-code none, as the run didn't finish
+        130
+    } else {
+        summary.exit_code()
+    };
+    Ok(code)
 }
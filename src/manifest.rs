@@ -0,0 +1,294 @@
+use crate::dirs::FileFormat;
+use crate::interop::{remote_url, same_url};
+use crate::options::SyncManifestOpts;
+use crate::paths::expand;
+use crate::renderers::cleanup_path;
+use crate::tag::update;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A checked-in list of the repositories making up a workspace, usually `dirmux.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// Where the repositories' paths are relative to, by default the manifest's directory
+    root: Option<String>,
+    /// Tags for every repository in the manifest
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default, rename = "repo")]
+    repos: Vec<ManifestRepo>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestRepo {
+    url: String,
+    /// Where to clone the repository, by default named after the last part of its URL
+    path: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// A repository from the manifest, with its path resolved
+#[derive(Debug, PartialEq)]
+struct Repo {
+    url: String,
+    path: PathBuf,
+    tags: Vec<String>,
+}
+
+/// What syncing did for a repository
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Cloned,
+    Present,
+    /// Would be cloned, if this weren't a dry run
+    Missing,
+    /// Something other than the manifest's repository is already there
+    Mismatched(String),
+    Failed(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Cloned => write!(f, "cloned"),
+            Outcome::Present => write!(f, "present"),
+            Outcome::Missing => write!(f, "missing, would clone"),
+            Outcome::Mismatched(reason) => write!(f, "mismatched, {}", reason),
+            Outcome::Failed(error) => write!(f, "failed, {}", error),
+        }
+    }
+}
+
+pub fn sync_manifest(opts: &SyncManifestOpts, filename: &Path) -> Result<()> {
+    let source = std::env::current_dir()?.join(&opts.file);
+    let repos = read_manifest(&source)?;
+
+    let mut outcomes = Vec::new();
+    for repo in &repos {
+        let outcome = sync(repo, opts.dry_run);
+        println!("{} ({})", cleanup_path(&repo.path)?, outcome);
+        outcomes.push(outcome);
+    }
+    let count = |f: fn(&Outcome) -> bool| outcomes.iter().filter(|o| f(o)).count();
+    let failed = count(|o| matches!(o, Outcome::Failed(_)));
+    println!(
+        "{} {}, {} present, {} mismatched, {} failed",
+        count(|o| matches!(o, Outcome::Cloned | Outcome::Missing)),
+        if opts.dry_run { "to clone" } else { "cloned" },
+        count(|o| *o == Outcome::Present),
+        count(|o| matches!(o, Outcome::Mismatched(_))),
+        failed
+    );
+
+    if !opts.dry_run {
+        update(filename, |file| Ok(register(file, &repos, &outcomes)))?;
+    }
+    if failed > 0 {
+        bail!("Couldn't clone {} repositories", failed);
+    }
+    Ok(())
+}
+
+fn read_manifest(source: &Path) -> Result<Vec<Repo>> {
+    let text = std::fs::read_to_string(source)
+        .with_context(|| format!("Couldn't read manifest: {}", source.display()))?;
+    let base = source.parent().unwrap_or_else(|| Path::new("."));
+    parse(&text, base).with_context(|| format!("Invalid manifest: {}", source.display()))
+}
+
+/// The repositories in a manifest, with relative paths taken to be under `base`
+fn parse(text: &str, base: &Path) -> Result<Vec<Repo>> {
+    let manifest: Manifest = toml::from_str(text)?;
+    let root = match &manifest.root {
+        Some(root) => expand(root, Some(base))?,
+        None => base.to_path_buf(),
+    };
+    let common = manifest.tags;
+    manifest
+        .repos
+        .into_iter()
+        .map(|repo| {
+            let path = match &repo.path {
+                Some(path) => path.clone(),
+                None => default_path(&repo.url)?,
+            };
+            let mut tags = common.clone();
+            tags.extend(repo.tags);
+            if tags.is_empty() {
+                bail!("{} has no tags", repo.url);
+            }
+            Ok(Repo {
+                path: expand(&path, Some(&root))?,
+                url: repo.url,
+                tags,
+            })
+        })
+        .collect()
+}
+
+/// The name git would clone `url` into, such as `api` for `git@example.com:team/api.git`
+fn default_path(url: &str) -> Result<String> {
+    let url = url.trim_end_matches('/');
+    let name = url.rsplit(['/', ':']).next().unwrap_or_default();
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    if name.is_empty() {
+        bail!("Can't name a directory after {}, give it a path", url);
+    }
+    Ok(name.to_string())
+}
+
+/// Clone the repository unless it is already there
+fn sync(repo: &Repo, dry_run: bool) -> Outcome {
+    if repo.path.exists() {
+        return match remote_url(&repo.path) {
+            Some(url) if same_url(&url, &repo.url) => Outcome::Present,
+            Some(url) => Outcome::Mismatched(format!("remote is {}", url)),
+            None if git2::Repository::open(&repo.path).is_ok() => {
+                Outcome::Mismatched(String::from("no remote"))
+            }
+            None => Outcome::Mismatched(String::from("not a git repository")),
+        };
+    }
+    if dry_run {
+        return Outcome::Missing;
+    }
+    match clone(&repo.url, &repo.path) {
+        Ok(()) => Outcome::Cloned,
+        Err(e) => Outcome::Failed(format!("{:#}", e)),
+    }
+}
+
+fn clone(url: &str, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let config = git2::Config::open_default().ok();
+    let mut callbacks = git2::RemoteCallbacks::new();
+    // libgit2 asks again each time credentials are rejected, so each kind is only offered once
+    let (mut tried_agent, mut tried_helper) = (false, false);
+    callbacks.credentials(move |url, username, allowed| match &config {
+        _ if allowed.contains(git2::CredentialType::SSH_KEY) && !tried_agent => {
+            tried_agent = true;
+            git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        }
+        Some(config)
+            if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) && !tried_helper =>
+        {
+            tried_helper = true;
+            git2::Cred::credential_helper(config, url, username)
+        }
+        _ if allowed.contains(git2::CredentialType::DEFAULT) => git2::Cred::default(),
+        _ => Err(git2::Error::from_str("No more credentials to try")),
+    });
+    let mut fetch = git2::FetchOptions::new();
+    fetch.remote_callbacks(callbacks);
+    git2::build::RepoBuilder::new()
+        .fetch_options(fetch)
+        .clone(url, path)?;
+    Ok(())
+}
+
+/// Tag every repository that is now on disk, leaving out anything mismatched with the manifest
+fn register(file: &FileFormat, repos: &[Repo], outcomes: &[Outcome]) -> FileFormat {
+    let mut f = file.clone();
+    let synced = repos
+        .iter()
+        .zip(outcomes)
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Cloned | Outcome::Present));
+    for (repo, _) in synced {
+        for tag in &repo.tags {
+            f.add(tag.clone(), &repo.path);
+        }
+    }
+    f
+}
+
+#[test]
+fn test_parse() -> Result<()> {
+    let text = r#"
+        root = "src"
+        tags = ["team"]
+
+        [[repo]]
+        url = "git@github.com:example/api.git"
+        tags = ["work/payments"]
+
+        [[repo]]
+        url = "https://example.com/tools/"
+        path = "/opt/tools"
+    "#;
+    assert_eq!(
+        vec![
+            Repo {
+                url: String::from("git@github.com:example/api.git"),
+                path: PathBuf::from("/team/src/api"),
+                tags: vec![String::from("team"), String::from("work/payments")],
+            },
+            Repo {
+                url: String::from("https://example.com/tools/"),
+                path: PathBuf::from("/opt/tools"),
+                tags: vec![String::from("team")],
+            },
+        ],
+        parse(text, Path::new("/team"))?
+    );
+    assert!(parse("[[repo]]\nurl = \"a/b\"\n", Path::new("/")).is_err());
+    assert!(parse("[[repo]]\nurl = \"a/b\"\ntag = [\"x\"]\n", Path::new("/")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_sync() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-manifest-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let upstream = git2::Repository::init(root.join("upstream/api"))?;
+    let signature = git2::Signature::now("dirmux", "dirmux@example.com")?;
+    let tree = upstream.find_tree(upstream.treebuilder(None)?.write()?)?;
+    upstream.commit(Some("HEAD"), &signature, &signature, "Initial", &tree, &[])?;
+    git2::Repository::init(root.join("workspace/other"))?;
+
+    let url = format!("file://{}", root.join("upstream/api").display());
+    let local = root.join("upstream/api").display().to_string();
+    std::fs::create_dir_all(root.join("workspace"))?;
+    std::fs::write(
+        root.join("workspace/dirmux.toml"),
+        format!(
+            "tags = [\"team\"]\n\
+             [[repo]]\nurl = \"{url}\"\n\
+             [[repo]]\nurl = \"{local}\"\npath = \"local\"\n\
+             [[repo]]\nurl = \"{url}\"\npath = \"other\"\n\
+             [[repo]]\nurl = \"{url}-missing\"\npath = \"broken\"\n"
+        ),
+    )?;
+    let repos = read_manifest(&root.join("workspace/dirmux.toml"))?;
+
+    let outcomes: Vec<Outcome> = repos.iter().map(|r| sync(r, true)).collect();
+    assert_eq!(Outcome::Missing, outcomes[0]);
+    assert!(!root.join("workspace/api").exists());
+
+    let outcomes: Vec<Outcome> = repos.iter().map(|r| sync(r, false)).collect();
+    assert_eq!(Outcome::Cloned, outcomes[0]);
+    assert_eq!(Outcome::Cloned, outcomes[1]);
+    assert_eq!(Outcome::Mismatched(String::from("no remote")), outcomes[2]);
+    assert!(matches!(outcomes[3], Outcome::Failed(_)));
+    assert!(root.join("workspace/api/.git").is_dir());
+
+    let outcomes: Vec<Outcome> = repos.iter().map(|r| sync(r, false)).collect();
+    assert_eq!(Outcome::Present, outcomes[0]);
+    assert_eq!(Outcome::Present, outcomes[1]);
+
+    let file = register(&FileFormat::blank(), &repos, &outcomes);
+    let tagged = file.tagged("team");
+    assert_eq!(2, tagged.len());
+    assert!(!tagged.contains(&&root.join("workspace/other")));
+    assert!(!tagged.contains(&&root.join("workspace/broken")));
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
@@ -1,4 +1,9 @@
+use crate::query::TagExpr;
+use anyhow::{anyhow, bail, Error};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
 /// This tool provides a convenient and fast interface to running commands across many directories,
@@ -15,9 +20,51 @@ use structopt::StructOpt;
 /// This will evaluate all the size of all the directories you have tagged.
 #[derive(Clone, Debug, PartialEq, StructOpt)]
 pub struct Options {
-    /// Which tag name to operate on
-    #[structopt(short)]
-    pub tag: Option<String>,
+    /// The config file holding the tagged directories
+    ///
+    /// Defaults to $DIRMUX_CONFIG, then $XDG_CONFIG_HOME/dirmux/config.json, or
+    /// ~/.config/dirmux/config.json.
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Use a separate, named set of tagged directories
+    ///
+    /// Each profile is kept in its own file in the 'profiles' directory next to the default config
+    /// file, so that e.g. personal and work directories can be kept apart. Defaults to
+    /// $DIRMUX_PROFILE, which is ignored if $DIRMUX_CONFIG is set.
+    #[structopt(long, conflicts_with = "config")]
+    pub profile: Option<String>,
+
+    /// Which tags to operate on
+    ///
+    /// This may be a single tag name or an expression combining tags with '&' (and), '|' (or), '!'
+    /// (not) and parentheses, such as '(frontend | backend) & !legacy'. Giving this more than once
+    /// selects directories matching any of the expressions.
+    #[structopt(short, number_of_values = 1)]
+    pub tag: Vec<TagExpr>,
+
+    /// Only operate on directories matching this glob, or under a directory matching it
+    ///
+    /// A leading '~' is expanded to your home directory. May be given more than once, in which case
+    /// a directory matching any of the includes is operated on.
+    #[structopt(long, number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Skip directories matching this glob, or under a directory matching it
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Only operate on directories whose path matches this regular expression
+    #[structopt(long, number_of_values = 1)]
+    pub include_regex: Vec<String>,
+
+    /// Skip directories whose path matches this regular expression
+    #[structopt(long, number_of_values = 1)]
+    pub exclude_regex: Vec<String>,
+
+    /// Only operate on directories at or below the current directory
+    #[structopt(long)]
+    pub here: bool,
 
     /// How many concurrent jobs to run
     ///
@@ -26,10 +73,89 @@ pub struct Options {
     #[structopt(short, long, default_value = "10")]
     pub jobs: usize,
 
+    /// Print command output live as it is produced
+    ///
+    /// Rather than waiting for each directory to finish, every line is printed as soon as it is
+    /// read, prefixed with the name of its directory. Lines from different directories are
+    /// interleaved.
+    #[structopt(short, long)]
+    pub stream: bool,
+
+    /// Write command output exactly as it was produced, without directory headers
+    ///
+    /// Output which isn't valid UTF-8 is otherwise shown with '\xNN' escapes. With --raw the
+    /// bytes are passed through untouched, for piping binary output on to another program. Each
+    /// directory's output is written when it finishes, or as it arrives with --stream.
+    #[structopt(long)]
+    pub raw: bool,
+
+    /// How to print the results: text, json or ndjson
+    ///
+    /// 'json' prints a single array once every directory has finished, and 'ndjson' prints one
+    /// object per line as each directory finishes. Each has the directory, its tags, exit code,
+    /// duration and output, and for 'status' and 'ffmerge' their results as separate fields.
+    #[structopt(long, default_value = "text", possible_values = &["text", "json", "ndjson"])]
+    pub output: OutputFormat,
+
+    /// What order to print results in: completion, path, tag or config
+    ///
+    /// By default results are printed as each directory finishes, so the order changes from run to
+    /// run. The other orders sort by directory path, by each directory's alphabetically first tag,
+    /// or by the order directories were added to the config file. Results are held back only until
+    /// those before them are done, so the first unfinished directory's output still streams.
+    #[structopt(long, default_value = "completion", possible_values = &["completion", "path", "tag", "config"])]
+    pub order: Order,
+
+    /// Give up on any directory which takes longer than this
+    ///
+    /// Accepts a number of seconds, or a number with an 'ms', 's', 'm' or 'h' suffix. Commands
+    /// which time out are killed along with any processes they started. Subcommands may override
+    /// this with their own --timeout.
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub timeout: Option<Duration>,
+
+    /// Pass commands on exactly as given, without expanding placeholders
+    ///
+    /// Normally '{dir}', '{name}', '{tag}', '{tags}', '{branch}', '{index}' and '{total}' in a
+    /// command are replaced with the details of the directory it is run in. Other braces are left
+    /// alone, as is anything between '{{' and '}}', except that '{{name}}' gives a literal
+    /// '{name}'.
+    #[structopt(long)]
+    pub no_template: bool,
+
+    /// How to summarise failing directories in dirmux's own exit code
+    ///
+    /// With 'count' the exit code is the number of directories that failed (capped at 255), with
+    /// 'first' it is the exit code of the first directory to fail, and with 'none' dirmux always
+    /// exits successfully once every directory has been run. Whatever the mode, a run stopped with
+    /// Ctrl-C exits with 130, as not every directory was run.
+    #[structopt(long, default_value = "count", possible_values = &["count", "first", "none"])]
+    pub exit_code: ExitCodeMode,
+
     #[structopt(subcommand)]
     pub cmd: Subcommands,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitCodeMode {
+    Count,
+    First,
+    None,
+}
+
+impl FromStr for ExitCodeMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "count" => Ok(ExitCodeMode::Count),
+            "first" => Ok(ExitCodeMode::First),
+            "none" => Ok(ExitCodeMode::None),
+            _ => bail!("Unknown exit code mode: {}", s),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, StructOpt)]
 pub enum Subcommands {
     /// Provides a compact git status
@@ -52,6 +178,26 @@ pub enum Subcommands {
     /// which tags.
     Tag(TagOpts),
 
+    /// Import directories and tags from gr, myrepos or a repo manifest
+    ///
+    /// Directories keep their tags from gr, and are tagged with their groups from a repo manifest.
+    /// Directories from myrepos or a repo manifest are also tagged with --tag, or by default 'mr'
+    /// or 'manifest', so that projects outside any group aren't lost.
+    /// Directories which don't exist, or whose git remote differs from the one imported, are
+    /// reported but still imported.
+    Import(ImportOpts),
+
+    /// Print the selected directories as a gr, myrepos or repo manifest config
+    Export(ExportOpts),
+
+    /// Clone and tag the repositories listed in a workspace manifest
+    ///
+    /// The manifest is a TOML file, usually checked in to a team repository, listing each
+    /// repository's URL along with the path to clone it to and its tags. Repositories which are
+    /// missing are cloned, and those already present are checked against the manifest's URL. All
+    /// of them are then tagged.
+    SyncManifest(SyncManifestOpts),
+
     /// Execute an arbitrary shell command
     ///
     /// This command is just here as a placeholder for the documentation, if the command doesn't
@@ -75,11 +221,55 @@ pub enum TagSubcommands {
     /// This option will check the existence of all the directories and remove tags from
     /// non-existing directories.
     Gc,
+    /// List all the tags and how many directories have each
+    List(TagListOpts),
+    /// Show the directories with a tag
+    ///
+    /// Each directory is marked with whether it exists and whether it is a git repository.
+    Show(TagShowOpts),
+    /// Show the tags on this directory
+    Which(TagWhichOpts),
+    /// Rename a tag
+    ///
+    /// Its sub-tags, rules and environment variables are renamed along with it.
+    Rename(TagRenameOpts),
+    /// Copy a tag, so its directories are also tagged with another tag
+    Copy(TagCopyOpts),
+    /// Merge several tags into one
+    ///
+    /// Used as 'dirmux tag merge <tag>... into <new-tag>'. The directories with any of the tags
+    /// are given the new tag, and the merged tags are removed.
+    Merge(TagMergeOpts),
+    /// Delete a tag from every directory
+    ///
+    /// Directories with other tags keep them, this only removes the one tag along with its
+    /// sub-tags, rules and environment variables.
+    Delete(TagDeleteOpts),
+    /// Find git repositories below a directory and tag them all
+    ///
+    /// Normal repositories, bare repositories and worktrees are all found. Once a repository is
+    /// found the scan doesn't look inside it for more, unless --nested is given.
+    Scan(TagScanOpts),
 }
 
 /// Test
 #[derive(Clone, Debug, PartialEq, StructOpt)]
-pub struct StatusOpts {}
+pub struct StatusOpts {
+    /// Give up on any directory which takes longer than this, overriding the global --timeout
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub timeout: Option<Duration>,
+
+    /// How to print the results: plain, table or json
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "table", "json"])]
+    pub format: ListFormat,
+
+    /// Print each repository's results through a template, such as '{dir} {branch} {modified}'
+    ///
+    /// The placeholders are '{dir}', '{name}' and the fields shown by '--format table', named in
+    /// lower case.
+    #[structopt(long, conflicts_with = "format")]
+    pub template: Option<String>,
+}
 
 #[derive(Clone, Debug, PartialEq, StructOpt)]
 pub struct MergeOpts {
@@ -90,6 +280,21 @@ pub struct MergeOpts {
     /// Print a verbose diffstat.
     #[structopt(short, long)]
     pub verbose: bool,
+
+    /// Give up on any directory which takes longer than this, overriding the global --timeout
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub timeout: Option<Duration>,
+
+    /// How to print the results: plain, table or json
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "table", "json"])]
+    pub format: ListFormat,
+
+    /// Print each repository's results through a template, such as '{dir} {branch} {outcome}'
+    ///
+    /// The placeholders are '{dir}', '{name}' and the fields shown by '--format table', named in
+    /// lower case.
+    #[structopt(long, conflicts_with = "format")]
+    pub template: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, StructOpt)]
@@ -104,6 +309,237 @@ pub struct TagRemoveOpts {
     pub path: Option<PathBuf>,
 }
 
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagListOpts {
+    /// How to print the tags: plain, table or json
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "table", "json"])]
+    pub format: ListFormat,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagShowOpts {
+    pub tag: String,
+
+    /// How to print the directories: plain, table or json
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "table", "json"])]
+    pub format: ListFormat,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagWhichOpts {
+    pub path: Option<PathBuf>,
+
+    /// How to print the tags: plain, table or json
+    #[structopt(long, default_value = "plain", possible_values = &["plain", "table", "json"])]
+    pub format: ListFormat,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagRenameOpts {
+    pub old: String,
+    pub new: String,
+
+    /// Replace the new tag if it already exists
+    #[structopt(short, long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagCopyOpts {
+    pub src: String,
+    pub dst: String,
+
+    /// Replace the destination tag if it already exists
+    #[structopt(short, long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagMergeOpts {
+    /// The tags to merge, followed by 'into' and the tag to merge them into
+    #[structopt(min_values = 3)]
+    pub args: Vec<String>,
+
+    /// Replace the tag being merged into if it already exists
+    #[structopt(short, long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagDeleteOpts {
+    pub tag: String,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct TagScanOpts {
+    /// The directory to search below
+    pub root: PathBuf,
+
+    /// The tag to add to each repository found
+    #[structopt(short, long)]
+    pub tag: String,
+
+    /// How many directories deep to search below the root
+    #[structopt(long)]
+    pub max_depth: Option<usize>,
+
+    /// Skip directories matching this .gitignore style pattern, such as 'node_modules' or
+    /// 'vendor/'. Patterns containing a '/' are matched relative to the root.
+    #[structopt(long, number_of_values = 1)]
+    pub skip: Vec<String>,
+
+    /// Also search inside the repositories found
+    #[structopt(long)]
+    pub nested: bool,
+
+    /// Only show which repositories would be tagged
+    #[structopt(short = "n", long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct ImportOpts {
+    /// The format of the file: gr, mr or manifest
+    #[structopt(possible_values = &["gr", "mr", "manifest"])]
+    pub format: ForeignFormat,
+
+    /// The file to import, such as ~/.grconfig.json, ~/.mrconfig or default.xml
+    pub file: PathBuf,
+
+    /// Tag every imported directory with this as well
+    #[structopt(short, long)]
+    pub tag: Option<String>,
+
+    /// The directory that a repo manifest's project paths are relative to, by default the one
+    /// holding its .repo directory, or the current directory
+    #[structopt(long)]
+    pub root: Option<PathBuf>,
+
+    /// Only show what would be imported
+    #[structopt(short = "n", long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct ExportOpts {
+    /// The format to print: gr, mr or manifest
+    #[structopt(possible_values = &["gr", "mr", "manifest"])]
+    pub format: ForeignFormat,
+
+    /// The directory that paths are written relative to, by default the home directory for
+    /// myrepos and the current directory for a repo manifest
+    #[structopt(long)]
+    pub root: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, StructOpt)]
+pub struct SyncManifestOpts {
+    /// The manifest to sync with
+    #[structopt(default_value = "dirmux.toml")]
+    pub file: PathBuf,
+
+    /// Only show what would be cloned
+    #[structopt(short = "n", long)]
+    pub dry_run: bool,
+}
+
+/// Config files of other multi-repository tools
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ForeignFormat {
+    /// gr's JSON file of tags
+    Gr,
+    /// myrepos' INI style file
+    Mr,
+    /// The Android repo tool's XML manifest
+    Manifest,
+}
+
+impl FromStr for ForeignFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gr" => Ok(ForeignFormat::Gr),
+            "mr" => Ok(ForeignFormat::Mr),
+            "manifest" => Ok(ForeignFormat::Manifest),
+            _ => bail!("Unknown format: {}", s),
+        }
+    }
+}
+
+/// Output formats for the tag inspection commands
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListFormat {
+    /// Space separated values, one item per line
+    Plain,
+    /// Aligned columns with headers
+    Table,
+    Json,
+}
+
+impl FromStr for ListFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(ListFormat::Plain),
+            "table" => Ok(ListFormat::Table),
+            "json" => Ok(ListFormat::Json),
+            _ => bail!("Unknown format: {}", s),
+        }
+    }
+}
+
+/// Orders for the results of running in directories
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// As each directory finishes
+    Completion,
+    Path,
+    /// By each directory's alphabetically first tag, then path
+    Tag,
+    /// As the directories are listed in the config file
+    Config,
+}
+
+impl FromStr for Order {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "completion" => Ok(Order::Completion),
+            "path" => Ok(Order::Path),
+            "tag" => Ok(Order::Tag),
+            "config" => Ok(Order::Config),
+            _ => bail!("Unknown order: {}", s),
+        }
+    }
+}
+
+/// Output formats for the results of running in directories
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Coloured, for people
+    Text,
+    /// A single JSON document at the end
+    Json,
+    /// A JSON object per line, as each directory finishes
+    Ndjson,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            _ => bail!("Unknown output format: {}", s),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, StructOpt)]
 pub struct TagOpts {
     #[structopt(subcommand)]
@@ -111,13 +547,61 @@ pub struct TagOpts {
 }
 
 #[derive(Clone, Debug, PartialEq, StructOpt)]
+#[structopt(setting = AppSettings::TrailingVarArg, setting = AppSettings::AllowLeadingHyphen)]
 pub struct ExecOpts {
-    #[structopt(subcommand)]
-    pub cmd: ExecCmd,
+    /// Kill any command which takes longer than this, overriding the global --timeout
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    pub timeout: Option<Duration>,
+
+    /// Pass the command on exactly as given, without expanding placeholders such as '{dir}'
+    #[structopt(long)]
+    pub no_template: bool,
+
+    /// Run this script with the shell, so it can use pipes, redirections and the like
+    ///
+    /// Any further arguments are quoted and added to the end of the script. Placeholders in the
+    /// script are quoted too, so write 'cd {dir}' rather than 'cd "{dir}"'. The shell is the one
+    /// set in the config file, otherwise $SHELL, otherwise sh.
+    #[structopt(long, conflicts_with = "script")]
+    pub shell: Option<String>,
+
+    /// Run this script file with the shell, passing it any further arguments
+    #[structopt(long, parse(from_os_str))]
+    pub script: Option<PathBuf>,
+
+    /// The command to run, or the arguments for --shell or --script
+    #[structopt(allow_hyphen_values = true)]
+    pub args: Vec<String>,
 }
 
-#[derive(Clone, Debug, PartialEq, StructOpt)]
-pub enum ExecCmd {
-    #[structopt(external_subcommand)]
-    RawCommand(Vec<String>),
+/// Parse a duration such as "90", "90s", "1500ms", "5m" or "1h"
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_alphabetic()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = match number.parse() {
+        Ok(number) if number >= 0.0 => number,
+        _ => bail!("Invalid duration: {}", s),
+    };
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => bail!("Unknown duration unit '{}' in: {}", unit, s),
+    };
+    Duration::try_from_secs_f64(seconds).map_err(|_| anyhow!("Duration is too long: {}", s))
+}
+
+#[test]
+fn test_parse_duration() -> Result<(), Error> {
+    assert_eq!(Duration::from_secs(90), parse_duration("90")?);
+    assert_eq!(Duration::from_secs(90), parse_duration("90s")?);
+    assert_eq!(Duration::from_millis(1500), parse_duration("1500ms")?);
+    assert_eq!(Duration::from_secs(300), parse_duration("5m")?);
+    assert_eq!(Duration::from_secs(5400), parse_duration("1.5h")?);
+    assert!(parse_duration("soon").is_err());
+    assert!(parse_duration("5d").is_err());
+    assert!(parse_duration(&format!("1{}h", "0".repeat(400))).is_err());
+    Ok(())
 }
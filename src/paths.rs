@@ -0,0 +1,157 @@
+use crate::renderers::cleanup_path;
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Replace a leading `~` with the home directory
+pub fn expand_home(pattern: &str) -> String {
+    match (pattern.strip_prefix('~'), dirs_next::home_dir()) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", home.display(), rest)
+        }
+        _ => pattern.to_string(),
+    }
+}
+
+/// Expand a leading `~` and any `$VAR` or `${VAR}` environment variables, as a shell would
+///
+/// A literal `$` is written as `$$`.
+pub fn expand_str(spelling: &str) -> Result<String> {
+    expand_str_with(spelling, |name| std::env::var(name).ok())
+}
+
+/// Expand `spelling` as `expand_str` does, looking up variables with `lookup`
+fn expand_str_with<F>(spelling: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let expanded = expand_home(spelling);
+    let mut out = String::new();
+    let mut rest = expanded.as_str();
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => bail!("Unclosed '${{' in path: {}", spelling),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            bail!("Expected a variable name after '$' in path: {}", spelling);
+        }
+        let value = lookup(name).with_context(|| {
+            format!(
+                "Environment variable {} isn't set for path: {}",
+                name, spelling
+            )
+        })?;
+        out.push_str(&value);
+        rest = after;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The absolute path for a directory as spelled in the config file
+///
+/// Relative paths are taken to be under `root`.
+pub fn expand(spelling: &str, root: Option<&Path>) -> Result<PathBuf> {
+    expand_with(spelling, root, |name| std::env::var(name).ok())
+}
+
+/// The absolute path for a directory as `expand` gives it, looking up variables with `lookup`
+pub fn expand_with<F>(spelling: &str, root: Option<&Path>, lookup: F) -> Result<PathBuf>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let path = PathBuf::from(expand_str_with(spelling, lookup)?);
+    if path.is_absolute() {
+        return Ok(path);
+    }
+    match root {
+        Some(root) => Ok(root.join(path)),
+        None => bail!(
+            "Relative path {} needs a root set in the config file",
+            spelling
+        ),
+    }
+}
+
+/// How to spell an absolute `path` in the config file so that it works on other machines
+///
+/// This is relative to `root` for paths under it, otherwise starting with `~/` for paths under the
+/// home directory. Any `$` in the path is escaped so it isn't taken for a variable.
+pub fn portable(path: &Path, root: Option<&Path>) -> Result<String> {
+    if let Some(root) = root {
+        let canonical = root.canonicalize().ok();
+        for root in std::iter::once(root).chain(canonical.as_deref()) {
+            match path.strip_prefix(root) {
+                Ok(rest) if !rest.as_os_str().is_empty() => {
+                    return Ok(rest.to_string_lossy().replace('$', "$$"))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(cleanup_path(path)?.replace('$', "$$"))
+}
+
+#[test]
+fn test_expand() -> Result<()> {
+    fn expand(spelling: &str, root: Option<&Path>) -> Result<PathBuf> {
+        expand_with(spelling, root, |name| match name {
+            "WORKSPACE" => Some(String::from("/srv/work")),
+            _ => None,
+        })
+    }
+    let root = Path::new("/src");
+    assert_eq!(
+        PathBuf::from("/srv/work/api"),
+        expand("$WORKSPACE/api", None)?
+    );
+    assert_eq!(
+        PathBuf::from("/srv/work-old/api"),
+        expand("${WORKSPACE}-old/api", None)?
+    );
+    assert_eq!(
+        PathBuf::from("/src/payments"),
+        expand("payments", Some(root))?
+    );
+    assert_eq!(PathBuf::from("/opt/x"), expand("/opt/x", Some(root))?);
+    assert!(expand("payments", None).is_err());
+    assert!(expand("$UNSET/api", None).is_err());
+    assert!(expand("${WORKSPACE/api", None).is_err());
+
+    if let Some(home) = dirs_next::home_dir() {
+        assert_eq!(home.join("blog"), expand("~/blog", None)?);
+        assert_eq!("~/blog", portable(&home.join("blog"), Some(root))?);
+    }
+    assert_eq!(
+        "payments",
+        portable(Path::new("/src/payments"), Some(root))?
+    );
+    assert_eq!("/src", portable(Path::new("/src"), Some(root))?);
+    assert_eq!("/opt/x", portable(Path::new("/opt/x"), None)?);
+
+    // A literal '$' survives a round trip
+    let odd = Path::new("/opt/$cash$$/x");
+    assert_eq!("/opt/$$cash$$$$/x", portable(odd, None)?);
+    assert_eq!(odd, expand(&portable(odd, None)?, None)?);
+    assert_eq!(
+        PathBuf::from("/src/$1"),
+        expand(&portable(Path::new("/src/$1"), Some(root))?, Some(root))?
+    );
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use anyhow::{bail, Error, Result};
+use std::collections::HashSet;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use std::str::FromStr;
+
+/// Separates the levels of hierarchical tags such as `work/payments`
+pub const SEPARATOR: char = '/';
+
+/// Whether `tag` is `parent` itself or one of its sub-tags
+pub fn is_within(tag: &str, parent: &str) -> bool {
+    tag.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(SEPARATOR))
+}
+
+/// A boolean expression over tag names, such as `work & rust & !archived`.
+///
+/// `&` binds tighter than `|`, `!` negates and parentheses group as usual. A tag name also selects
+/// directories with any of its sub-tags, so `work` matches `work/payments`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagExpr {
+    Tag(String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+impl TagExpr {
+    /// Whether a directory with the given `tags` is selected by this expression
+    pub fn matches(&self, tags: &HashSet<&str>) -> bool {
+        match self {
+            TagExpr::Tag(tag) => tags.iter().any(|t| is_within(t, tag)),
+            TagExpr::Not(expr) => !expr.matches(tags),
+            TagExpr::And(lhs, rhs) => lhs.matches(tags) && rhs.matches(tags),
+            TagExpr::Or(lhs, rhs) => lhs.matches(tags) || rhs.matches(tags),
+        }
+    }
+
+    /// Every tag named in the expression
+    pub fn tags(&self) -> Vec<&str> {
+        match self {
+            TagExpr::Tag(tag) => vec![tag.as_str()],
+            TagExpr::Not(expr) => expr.tags(),
+            TagExpr::And(lhs, rhs) | TagExpr::Or(lhs, rhs) => {
+                let mut tags = lhs.tags();
+                tags.extend(rhs.tags());
+                tags
+            }
+        }
+    }
+}
+
+impl FromStr for TagExpr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            input: s,
+            chars: s.char_indices().peekable(),
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some((pos, c)) => bail!("Unexpected '{}' at {} in tag expression: {}", c, pos, s),
+        }
+    }
+}
+
+impl fmt::Display for TagExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagExpr::Tag(tag) => write!(f, "{}", tag),
+            TagExpr::Not(expr) => write!(f, "!{}", expr),
+            TagExpr::And(lhs, rhs) => write!(f, "({} & {})", lhs, rhs),
+            TagExpr::Or(lhs, rhs) => write!(f, "({} | {})", lhs, rhs),
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    /// The next significant character, skipping whitespace
+    fn peek(&mut self) -> Option<(usize, char)> {
+        while let Some((_, c)) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+        self.chars.peek().copied()
+    }
+
+    fn or(&mut self) -> Result<TagExpr> {
+        let mut expr = self.and()?;
+        while let Some((_, '|')) = self.peek() {
+            self.chars.next();
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<TagExpr> {
+        let mut expr = self.unary()?;
+        while let Some((_, '&')) = self.peek() {
+            self.chars.next();
+            expr = TagExpr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<TagExpr> {
+        match self.peek() {
+            Some((_, '!')) => {
+                self.chars.next();
+                Ok(TagExpr::Not(Box::new(self.unary()?)))
+            }
+            Some((pos, '(')) => {
+                self.chars.next();
+                let expr = self.or()?;
+                match self.peek() {
+                    Some((_, ')')) => {
+                        self.chars.next();
+                        Ok(expr)
+                    }
+                    _ => bail!("Unclosed '(' at {} in tag expression: {}", pos, self.input),
+                }
+            }
+            Some((start, c)) if is_tag_char(c) => {
+                let mut end = start;
+                while let Some(&(pos, c)) = self.chars.peek() {
+                    if !is_tag_char(c) {
+                        break;
+                    }
+                    end = pos + c.len_utf8();
+                    self.chars.next();
+                }
+                Ok(TagExpr::Tag(self.input[start..end].to_string()))
+            }
+            Some((pos, c)) => bail!(
+                "Unexpected '{}' at {} in tag expression: {}",
+                c,
+                pos,
+                self.input
+            ),
+            None => bail!("Tag expression ended early: {}", self.input),
+        }
+    }
+}
+
+fn is_tag_char(c: char) -> bool {
+    !c.is_whitespace() && !"&|!()".contains(c)
+}
+
+#[test]
+fn test_parse() -> Result<()> {
+    let tag = |t: &str| Box::new(TagExpr::Tag(t.to_string()));
+    assert_eq!(TagExpr::Tag("work".to_string()), "work".parse()?);
+    assert_eq!(
+        TagExpr::Or(
+            tag("a"),
+            Box::new(TagExpr::And(tag("b"), Box::new(TagExpr::Not(tag("c")))))
+        ),
+        "a | b & !c".parse()?
+    );
+    assert_eq!(
+        "((frontend | backend) & !legacy)",
+        "(frontend|backend)&!legacy".parse::<TagExpr>()?.to_string()
+    );
+    assert!("a &".parse::<TagExpr>().is_err());
+    assert!("(a | b".parse::<TagExpr>().is_err());
+    assert!("a b".parse::<TagExpr>().is_err());
+    assert_eq!(vec!["a", "b", "c"], "a | b & !c".parse::<TagExpr>()?.tags());
+    Ok(())
+}
+
+#[test]
+fn test_matches() -> Result<()> {
+    let expr: TagExpr = "work & rust & !archived".parse()?;
+    assert!(expr.matches(&["work", "rust"].iter().copied().collect()));
+    assert!(!expr.matches(&["work", "rust", "archived"].iter().copied().collect()));
+    assert!(!expr.matches(&["work"].iter().copied().collect()));
+
+    let expr: TagExpr = "work & !work/legacy".parse()?;
+    assert!(expr.matches(&["work/payments"].iter().copied().collect()));
+    assert!(!expr.matches(&["work/legacy/api"].iter().copied().collect()));
+    assert!(!expr.matches(&["workshop"].iter().copied().collect()));
+    Ok(())
+}
@@ -1,17 +1,49 @@
+use crate::cmds::git::{MergeDetails, MergeOutcome, StatusDetails};
+use crate::styling::Style;
+use crate::template;
 use crate::CommandMessage;
+use crate::CommandOutput;
+use crate::Details;
 use crate::Renderer;
+use crate::Target;
 use anyhow::Result;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use termion::{color, style};
 
 #[derive(Default)]
-pub struct NullRender {}
+pub struct NullRender {
+    /// The branch each directory is expected to be on, where it isn't master or main
+    default_branches: HashMap<PathBuf, String>,
+}
+
+impl NullRender {
+    pub fn new(targets: &[Target]) -> Self {
+        let default_branches = targets
+            .iter()
+            .filter_map(|t| Some((t.dir.clone(), t.default_branch.clone()?)))
+            .collect();
+        NullRender { default_branches }
+    }
+}
 
 impl Renderer for NullRender {
     fn process(&self, msg: CommandMessage) -> Result<()> {
         match msg {
-            CommandMessage::Final(Ok(msg)) => print!("{}", msg.output),
-            CommandMessage::Final(Err(msg)) => eprint!("{}", msg),
+            CommandMessage::Final(Ok(msg)) => {
+                let default_branch = self.default_branches.get(&msg.dir).map(String::as_str);
+                print!("{}", text(&msg, default_branch)?);
+                if !msg.status.is_success() {
+                    eprintln!("{}: {}", cleanup_path(&msg.dir)?, failure_text(&msg));
+                }
+            }
+            CommandMessage::Final(Err(failure)) => eprintln!("{}", failure),
             _ => {}
         };
         Ok(())
@@ -28,24 +60,48 @@ impl Renderer for SimpleSectionRender {
         match msg {
             CommandMessage::Final(Ok(msg)) => {
                 let newline = if self.single_line { " " } else { "\n" };
-                if msg.output.len() > 0 {
+                let failed = !msg.status.is_success();
+                let output = text(&msg, None)?;
+                if !output.is_empty() || failed {
                     print!(
-                        "{}{}{}:{}{}",
+                        "{}{}{}{}:{}{}",
                         color::Fg(color::Rgb(200, 196, 0)),
                         style::Bold,
                         cleanup_path(&msg.dir)?,
+                        failure_marker(&msg),
                         style::Reset,
                         newline,
                     );
-                    print!("{}", msg.output);
+                    print!("{}", output);
+                    if failed && output.is_empty() && self.single_line {
+                        println!();
+                    }
                 }
-                if msg.error.len() > 0 {
+                if !msg.error.is_empty() {
                     eprint!("{}:{}", msg.dir.display(), newline);
-                    eprint!("{}", msg.error);
+                    eprint!("{}", escaped(&msg.error));
                 }
             }
-            CommandMessage::Final(Err(msg)) => {
-                eprintln!("Err: {}", msg);
+            CommandMessage::Final(Err(failure)) => {
+                let newline = if self.single_line { " " } else { "\n" };
+                eprint!(
+                    "{}{}{} {}[{}]:{}{}",
+                    color::Fg(color::Rgb(200, 196, 0)),
+                    style::Bold,
+                    cleanup_path(&failure.dir)?,
+                    color::Fg(color::Red),
+                    failure.kind,
+                    style::Reset,
+                    newline,
+                );
+                if self.single_line {
+                    eprintln!("{}", failure.chain().collect::<Vec<_>>().join(": "));
+                } else {
+                    for (i, cause) in failure.chain().enumerate() {
+                        let prefix = if i == 0 { "" } else { "  caused by: " };
+                        eprintln!("{}{}", prefix, cause);
+                    }
+                }
             }
             _ => {}
         }
@@ -59,7 +115,544 @@ impl SimpleSectionRender {
     }
 }
 
-pub fn cleanup_path(path: &PathBuf) -> Result<String> {
+/// Prints each line as it arrives, prefixed by its directory, as `docker compose logs` does.
+pub struct PrefixRender {
+    width: usize,
+}
+
+impl PrefixRender {
+    pub fn new(targets: &[Target]) -> Result<Self> {
+        let mut width = 0;
+        for target in targets {
+            width = std::cmp::max(width, cleanup_path(&target.dir)?.chars().count());
+        }
+        Ok(PrefixRender { width })
+    }
+
+    fn prefix(&self, dir: &Path) -> Result<String> {
+        const PALETTE: [color::AnsiValue; 6] = [
+            color::AnsiValue(6),
+            color::AnsiValue(3),
+            color::AnsiValue(2),
+            color::AnsiValue(5),
+            color::AnsiValue(4),
+            color::AnsiValue(1),
+        ];
+        let name = cleanup_path(dir)?;
+        let hash = name.bytes().fold(0usize, |acc, b| {
+            acc.wrapping_mul(31).wrapping_add(b as usize)
+        });
+        Ok(format!(
+            "{}{:width$} |{}",
+            color::Fg(PALETTE[hash % PALETTE.len()]),
+            name,
+            style::Reset,
+            width = self.width,
+        ))
+    }
+}
+
+impl Renderer for PrefixRender {
+    fn process(&self, msg: CommandMessage) -> Result<()> {
+        match msg {
+            CommandMessage::Increment(msg) => {
+                let prefix = self.prefix(&msg.dir)?;
+                if !msg.output.is_empty() {
+                    print!("{} {}", prefix, with_newline(escaped(&msg.output)));
+                }
+                if !msg.error.is_empty() {
+                    eprint!("{} {}", prefix, with_newline(escaped(&msg.error)));
+                }
+            }
+            CommandMessage::Final(Ok(msg)) if !msg.status.is_success() => {
+                println!(
+                    "{}{}{}",
+                    self.prefix(&msg.dir)?,
+                    failure_marker(&msg),
+                    style::Reset
+                );
+            }
+            CommandMessage::Final(Err(failure)) => {
+                eprintln!(
+                    "{}{} [{}]{} {:#}",
+                    self.prefix(&failure.dir)?,
+                    color::Fg(color::Red),
+                    failure.kind,
+                    style::Reset,
+                    failure.error,
+                );
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Passes output through exactly as the commands wrote it, without headers or colours.
+///
+/// Errors and failures still go to stderr, so that stdout holds only what the commands printed.
+pub struct RawRender {
+    /// Output arrives as increments, so has been written before a directory finishes
+    stream: bool,
+}
+
+impl RawRender {
+    pub fn new(stream: bool) -> Self {
+        RawRender { stream }
+    }
+
+    fn write(&self, msg: &CommandOutput) -> Result<()> {
+        std::io::stdout().write_all(&msg.output)?;
+        std::io::stderr().write_all(&msg.error)?;
+        Ok(())
+    }
+}
+
+impl Renderer for RawRender {
+    fn process(&self, msg: CommandMessage) -> Result<()> {
+        match msg {
+            CommandMessage::Increment(msg) if self.stream => self.write(&msg)?,
+            CommandMessage::Final(Ok(msg)) => {
+                if !self.stream {
+                    self.write(&msg)?;
+                }
+                if !msg.status.is_success() {
+                    std::io::stdout().flush()?;
+                    eprintln!("{}: {}", cleanup_path(&msg.dir)?, failure_text(&msg));
+                }
+            }
+            CommandMessage::Final(Err(failure)) => eprintln!("{}", failure),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Prints a JSON record for each directory, for scripts and dashboards rather than people.
+pub struct JsonRender {
+    tags: HashMap<PathBuf, Vec<String>>,
+    /// Print each record on a line of its own as soon as its directory finishes
+    ndjson: bool,
+    /// Records waiting to be printed together at the end
+    records: Mutex<Vec<Record>>,
+}
+
+/// What happened in a single directory
+#[derive(Debug, Serialize)]
+struct Record {
+    dir: String,
+    tags: Vec<String>,
+    /// Missing if the command couldn't be run or didn't finish
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    duration_ms: u128,
+    /// The command's output, left out for built-in commands in favour of their details
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stderr: Option<String>,
+    #[serde(flatten)]
+    details: Option<Details>,
+    /// The kind of failure, if the directory failed without an exit code
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl JsonRender {
+    pub fn new(targets: &[Target], ndjson: bool) -> Self {
+        JsonRender {
+            tags: targets
+                .iter()
+                .map(|target| (target.dir.clone(), target.tags.clone()))
+                .collect(),
+            ndjson,
+            records: Mutex::new(vec![]),
+        }
+    }
+
+    fn record(&self, dir: &Path, duration: Duration) -> Record {
+        Record {
+            dir: dir.to_string_lossy().to_string(),
+            tags: self.tags.get(dir).cloned().unwrap_or_default(),
+            exit_code: None,
+            duration_ms: duration.as_millis(),
+            stdout: None,
+            stderr: None,
+            details: None,
+            failure: None,
+            error: None,
+        }
+    }
+}
+
+impl Renderer for JsonRender {
+    fn process(&self, msg: CommandMessage) -> Result<()> {
+        let record = match msg {
+            CommandMessage::Final(Ok(msg)) => {
+                let text = |bytes: &[u8]| match msg.details {
+                    Some(_) => None,
+                    None => Some(escaped(bytes).into_owned()),
+                };
+                Record {
+                    exit_code: Some(msg.status.code()),
+                    stdout: text(&msg.output),
+                    stderr: text(&msg.error),
+                    details: msg.details.clone(),
+                    ..self.record(&msg.dir, msg.duration)
+                }
+            }
+            CommandMessage::Final(Err(failure)) => Record {
+                failure: Some(failure.kind.to_string()),
+                error: Some(failure.chain().collect::<Vec<_>>().join(": ")),
+                ..self.record(&failure.dir, failure.duration)
+            },
+            _ => return Ok(()),
+        };
+        if self.ndjson {
+            println!("{}", serde_json::to_string(&record)?);
+        } else {
+            self.records.lock().unwrap().push(record);
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        if !self.ndjson {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&*self.records.lock().unwrap())?
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Holds back each directory's messages until every directory before it has finished.
+///
+/// Messages for the first unfinished directory go straight through, so output still streams for
+/// one directory at a time while the order stays the same from run to run.
+pub struct OrderedRender {
+    inner: Arc<dyn Renderer>,
+    positions: HashMap<PathBuf, usize>,
+    pending: Mutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    /// The position of the first directory which hasn't finished
+    next: usize,
+    held: BTreeMap<usize, Vec<CommandMessage>>,
+    finished: HashSet<usize>,
+}
+
+impl OrderedRender {
+    /// Order messages as the `targets` are ordered
+    pub fn new(inner: Arc<dyn Renderer>, targets: &[Target]) -> Self {
+        OrderedRender {
+            inner,
+            positions: targets
+                .iter()
+                .enumerate()
+                .map(|(i, target)| (target.dir.clone(), i))
+                .collect(),
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+}
+
+impl Renderer for OrderedRender {
+    fn process(&self, msg: CommandMessage) -> Result<()> {
+        let position = match self.positions.get(msg.dir()) {
+            Some(position) => *position,
+            None => return self.inner.process(msg),
+        };
+        let finished = matches!(msg, CommandMessage::Final(_));
+        let mut pending = self.pending.lock().unwrap();
+        if position != pending.next {
+            pending.held.entry(position).or_default().push(msg);
+            if finished {
+                pending.finished.insert(position);
+            }
+            return Ok(());
+        }
+
+        self.inner.process(msg)?;
+        if finished {
+            pending.next += 1;
+            // Catch up on the directories which finished while waiting their turn
+            loop {
+                let next = pending.next;
+                for msg in pending.held.remove(&next).unwrap_or_default() {
+                    self.inner.process(msg)?;
+                }
+                if !pending.finished.remove(&next) {
+                    break;
+                }
+                pending.next += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        // Directories which never finished, such as after Ctrl-C, still show what they did
+        let held = std::mem::take(&mut self.pending.lock().unwrap().held);
+        for msg in held.into_values().flatten() {
+            self.inner.process(msg)?;
+        }
+        self.inner.finish()
+    }
+}
+
+/// Lays out the results of a built-in command in columns, once every directory has finished.
+#[derive(Default)]
+pub struct TableRender {
+    rows: Mutex<Vec<(PathBuf, Details)>>,
+}
+
+impl Renderer for TableRender {
+    fn process(&self, msg: CommandMessage) -> Result<()> {
+        match msg {
+            CommandMessage::Final(Ok(msg)) => {
+                if let Some(details) = msg.details {
+                    self.rows.lock().unwrap().push((msg.dir, details));
+                }
+            }
+            CommandMessage::Final(Err(failure)) => eprintln!("{}", failure),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        let rows = self.rows.lock().unwrap();
+        let first = match rows.first() {
+            Some((_, details)) => details.fields(),
+            None => return Ok(()),
+        };
+        let headers: Vec<String> = std::iter::once("directory")
+            .chain(first.iter().map(|(name, _)| *name))
+            .map(|name| name.to_uppercase())
+            .collect();
+        let headers: Vec<&str> = headers.iter().map(String::as_str).collect();
+        let mut table = Vec::new();
+        for (dir, details) in rows.iter() {
+            let mut row = vec![cleanup_path(dir)?];
+            row.extend(details.fields().into_iter().map(|(_, value)| value));
+            table.push(row);
+        }
+        print!("{}", format_table(&headers, &table));
+        Ok(())
+    }
+}
+
+/// Prints the results of a built-in command for each directory through a template.
+///
+/// The placeholders are `{dir}`, `{name}` and each of the command's fields, such as `{branch}`.
+pub struct TemplateRender {
+    template: String,
+}
+
+impl TemplateRender {
+    pub fn new(template: &str) -> Self {
+        TemplateRender {
+            template: template.to_string(),
+        }
+    }
+
+    fn expand(&self, dir: &Path, details: &Details) -> Result<String> {
+        let dir_text = cleanup_path(dir)?;
+        let fields = details.fields();
+        Ok(template::expand(&self.template, |name| match name {
+            "dir" => Some(dir_text.clone()),
+            "name" => dir.file_name().map(|n| n.to_string_lossy().to_string()),
+            _ => fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.clone()),
+        }))
+    }
+}
+
+impl Renderer for TemplateRender {
+    fn process(&self, msg: CommandMessage) -> Result<()> {
+        match msg {
+            CommandMessage::Final(Ok(msg)) => {
+                if let Some(details) = &msg.details {
+                    println!("{}", self.expand(&msg.dir, details)?);
+                }
+            }
+            CommandMessage::Final(Err(failure)) => eprintln!("{}", failure),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The text to show for a directory: its command's output, or a built-in command's results
+fn text<'a>(msg: &'a CommandOutput, default_branch: Option<&str>) -> Result<Cow<'a, str>> {
+    Ok(match &msg.details {
+        Some(Details::Status(status)) => {
+            Cow::Owned(format_status(&msg.dir, status, default_branch)?)
+        }
+        Some(Details::Ffmerge(merge)) => Cow::Owned(format_merge(merge)),
+        None => escaped(&msg.output),
+    })
+}
+
+/// The one line summary of a repository, or nothing if it is clean and on its default branch
+///
+/// Without a default branch, either master or main will do.
+fn format_status(
+    dir: &Path,
+    details: &StatusDetails,
+    default_branch: Option<&str>,
+) -> Result<String> {
+    let shorthand = details.branch.as_deref().unwrap_or("");
+    let mut output: Vec<String> = vec![];
+    let mut char_count = 0;
+    char_count += status_fmt(&mut output, "A", details.added, "git-added");
+    char_count += status_fmt(&mut output, "M", details.modified, "git-modified");
+    char_count += status_fmt(&mut output, "D", details.deleted, "git-deleted");
+    char_count += status_fmt(&mut output, "R", details.renamed, "git-renamed");
+    char_count += status_fmt(&mut output, "T", details.typechanged, "git-typechanged");
+    char_count += status_fmt(&mut output, "!", details.ignored, "git-ignored");
+    char_count += status_fmt(&mut output, "C", details.conflicted, "git-conflicted");
+    char_count += status_fmt(&mut output, "?", details.untracked, "git-unknown");
+
+    let statuses = output.join(" ");
+    if output.len() > 1 {
+        char_count += output.len() - 1;
+    }
+
+    let mut output = String::from("");
+    let on_default = match default_branch {
+        Some(default_branch) => shorthand == default_branch,
+        None => shorthand == "master" || shorthand == "main",
+    };
+    if statuses.is_empty() && on_default {
+        Ok(output)
+    } else {
+        output.push_str(format!("{:>20} ", cleanup_path(dir)?).as_ref());
+        output.push_str(&statuses);
+        let statuses_width = 12;
+        let padding = statuses_width - std::cmp::min(statuses_width, char_count);
+        output.push_str(format!("{:width$}", "", width = padding).as_ref());
+        output.push_str(format!(" {:12}", shorthand).as_ref());
+        output.push('\n');
+        Ok(output)
+    }
+}
+
+fn status_fmt(output: &mut Vec<String>, suff: &str, count: u32, style: &str) -> usize {
+    if count > 0 {
+        let style = Style::id(style);
+        output.push(format!(
+            "{}{}{}{}",
+            style.before(),
+            count,
+            suff,
+            style.after()
+        ));
+        format!("{}{}", count, suff).len()
+    } else {
+        0
+    }
+}
+
+/// What `ffmerge` did, or nothing if there was nothing to do
+fn format_merge(details: &MergeDetails) -> String {
+    let mut output = String::new();
+    match details.outcome {
+        MergeOutcome::FastForwarded | MergeOutcome::WouldFastForward => {
+            if let Some(diffstat) = &details.diffstat {
+                output.push('\n');
+                output.push_str(diffstat);
+            }
+            output.push_str(&format!(
+                "Fast-Forward: Setting refs/heads/{} to id: {}\n",
+                details.branch.as_deref().unwrap_or_default(),
+                details.to.as_deref().unwrap_or_default()
+            ));
+        }
+        MergeOutcome::Diverged => output.push_str("Cannot fast-forward\n"),
+        _ => {}
+    }
+    output
+}
+
+fn with_newline(line: Cow<'_, str>) -> Cow<'_, str> {
+    if line.ends_with('\n') {
+        line
+    } else {
+        Cow::Owned(format!("{}\n", line))
+    }
+}
+
+/// Command output as text, with any bytes which aren't valid UTF-8 shown as `\xNN` escapes
+pub fn escaped(bytes: &[u8]) -> Cow<'_, str> {
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return Cow::Borrowed(text);
+    }
+    let mut text = String::new();
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    Cow::Owned(text)
+}
+
+/// A bracketed, red marker for a directory header when its command failed
+fn failure_marker(msg: &CommandOutput) -> String {
+    if msg.status.is_success() {
+        String::new()
+    } else {
+        format!(" {}[{}]", color::Fg(color::Red), msg.status)
+    }
+}
+
+/// Describe a failed directory, preferring what the command itself said
+fn failure_text(msg: &CommandOutput) -> String {
+    match escaped(&msg.error).lines().last() {
+        Some(line) => format!("{} ({})", line, msg.status),
+        None => msg.status.to_string(),
+    }
+}
+
+/// Lay out `rows` in left-aligned columns beneath `headers`
+pub fn format_table<S: AsRef<str>>(headers: &[&str], rows: &[Vec<S>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = std::cmp::max(*width, cell.as_ref().chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let mut line = |cells: Vec<&str>| {
+        let mut text = String::new();
+        for (i, (cell, width)) in cells.iter().zip(&widths).enumerate() {
+            if i + 1 == cells.len() {
+                text.push_str(cell);
+            } else {
+                text.push_str(&format!("{:width$}  ", cell, width = width));
+            }
+        }
+        out.push_str(text.trim_end());
+        out.push('\n');
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(|c| c.as_ref()).collect());
+    }
+    out
+}
+
+pub fn cleanup_path(path: &Path) -> Result<String> {
     let res = match dirs_next::home_dir() {
         Some(homedir) => {
             if path.starts_with(&homedir) {
@@ -75,3 +668,242 @@ pub fn cleanup_path(path: &PathBuf) -> Result<String> {
 
     Ok(res)
 }
+
+#[test]
+fn test_escaped() {
+    assert_eq!("plain ✓\n", escaped("plain ✓\n".as_bytes()));
+    assert_eq!("caf\\xe9 \\xff\\xfe ok", escaped(b"caf\xe9 \xff\xfe ok"));
+    assert_eq!("\\xe2\\x9c", escaped(&"✓".as_bytes()[..2]));
+}
+
+#[test]
+fn test_failure_text() {
+    let output = |error: &[u8]| CommandOutput {
+        dir: PathBuf::from("/src/api"),
+        output: vec![],
+        error: error.to_vec(),
+        status: crate::ExitState::Code(2),
+        details: None,
+        duration: Duration::ZERO,
+    };
+    assert_eq!("exit 2", failure_text(&output(b"")));
+    assert_eq!(
+        "caf\\xe9 broke (exit 2)",
+        failure_text(&output(b"warning\ncaf\xe9 broke\n"))
+    );
+}
+
+#[test]
+fn test_json_records() -> Result<()> {
+    use crate::failure::DirFailure;
+    use crate::ExitState;
+
+    let target = |dir: &str, tags: &[&str]| Target {
+        dir: PathBuf::from(dir),
+        alias: None,
+        default_branch: None,
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+        env: Default::default(),
+        index: 1,
+        total: 3,
+    };
+    let targets = vec![
+        target("/src/api", &["work"]),
+        target("/src/site", &[]),
+        target("/src/gone", &["old"]),
+    ];
+    let render = JsonRender::new(&targets, false);
+    render.process(CommandMessage::Final(Ok(CommandOutput {
+        dir: PathBuf::from("/src/api"),
+        output: b"caf\xe9\n".to_vec(),
+        error: vec![],
+        status: ExitState::Code(2),
+        details: None,
+        duration: Duration::from_millis(1500),
+    })))?;
+    render.process(CommandMessage::Final(Ok(CommandOutput {
+        dir: PathBuf::from("/src/site"),
+        output: b"pre-formatted".to_vec(),
+        error: vec![],
+        status: ExitState::Success,
+        details: Some(Details::Status(StatusDetails {
+            branch: Some(String::from("main")),
+            modified: 2,
+            ..StatusDetails::default()
+        })),
+        duration: Duration::ZERO,
+    })))?;
+    render.process(CommandMessage::Final(Err(DirFailure::new(
+        PathBuf::from("/src/gone"),
+        anyhow::anyhow!("No such directory"),
+    ))))?;
+
+    let records = serde_json::to_value(&*render.records.lock().unwrap())?;
+    assert_eq!(
+        serde_json::json!({
+            "dir": "/src/api",
+            "tags": ["work"],
+            "exit_code": 2,
+            "duration_ms": 1500,
+            "stdout": "caf\\xe9\n",
+            "stderr": "",
+        }),
+        records[0]
+    );
+    assert_eq!(None, records[1].get("stdout"));
+    assert_eq!(2, records[1]["status"]["modified"]);
+    assert_eq!("main", records[1]["status"]["branch"]);
+    assert_eq!(None, records[2].get("exit_code"));
+    assert_eq!(vec!["old"], records[2]["tags"].as_array().unwrap().clone());
+    assert_eq!("No such directory", records[2]["error"]);
+    Ok(())
+}
+
+#[test]
+fn test_details_views() -> Result<()> {
+    let mut merge = MergeDetails {
+        branch: Some(String::from("main")),
+        upstream: Some(String::from("origin/main")),
+        to: Some(String::from("34755a7e")),
+        insertions: Some(3),
+        ..MergeDetails::new(MergeOutcome::WouldFastForward)
+    };
+    assert_eq!(
+        "Fast-Forward: Setting refs/heads/main to id: 34755a7e\n",
+        format_merge(&merge)
+    );
+    merge.diffstat = Some(String::from(" g | 3 +++\n"));
+    assert_eq!(
+        "\n g | 3 +++\nFast-Forward: Setting refs/heads/main to id: 34755a7e\n",
+        format_merge(&merge)
+    );
+    merge.outcome = MergeOutcome::UpToDate;
+    assert_eq!("", format_merge(&merge));
+
+    let render = TemplateRender::new("{name} {branch}->{upstream} {outcome} +{insertions} {nope}");
+    assert_eq!(
+        "api main->origin/main up_to_date +3 {nope}",
+        render.expand(Path::new("/src/api"), &Details::Ffmerge(merge))?
+    );
+
+    let clean = StatusDetails {
+        branch: Some(String::from("main")),
+        ..StatusDetails::default()
+    };
+    let api = Path::new("/src/api");
+    assert_eq!("", format_status(api, &clean, None)?);
+    let feature = StatusDetails {
+        branch: Some(String::from("feature")),
+        ..clean.clone()
+    };
+    assert!(format_status(api, &feature, None)?.contains("/src/api"));
+
+    // A repository developed on another branch is clean there, and not on main
+    let develop = StatusDetails {
+        branch: Some(String::from("develop")),
+        ..clean.clone()
+    };
+    assert_eq!("", format_status(api, &develop, Some("develop"))?);
+    assert!(format_status(api, &clean, Some("develop"))?.contains("main"));
+    let render = NullRender::new(&[Target {
+        dir: api.to_path_buf(),
+        alias: None,
+        default_branch: Some(String::from("develop")),
+        tags: vec![],
+        env: Default::default(),
+        index: 1,
+        total: 1,
+    }]);
+    assert_eq!(
+        Some(&String::from("develop")),
+        render.default_branches.get(api)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_ordered() -> Result<()> {
+    use crate::ExitState;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+    impl Renderer for Recorder {
+        fn process(&self, msg: CommandMessage) -> Result<()> {
+            let kind = match &msg {
+                CommandMessage::Started(_) => "started",
+                CommandMessage::Final(_) => "final",
+                _ => "increment",
+            };
+            let name = msg.dir().display().to_string();
+            self.0.lock().unwrap().push(format!("{} {}", name, kind));
+            Ok(())
+        }
+    }
+
+    let targets: Vec<Target> = ["/a", "/b", "/c", "/d"]
+        .iter()
+        .map(|dir| Target {
+            dir: PathBuf::from(dir),
+            alias: None,
+            default_branch: None,
+            tags: vec![],
+            env: Default::default(),
+            index: 0,
+            total: 4,
+        })
+        .collect();
+    let recorder = Arc::new(Recorder::default());
+    let render = OrderedRender::new(recorder.clone(), &targets);
+    let output = |dir: &str, status: ExitState| CommandOutput {
+        dir: PathBuf::from(dir),
+        output: vec![],
+        error: vec![],
+        status,
+        details: None,
+        duration: Duration::ZERO,
+    };
+    let take = || std::mem::take(&mut *recorder.0.lock().unwrap());
+
+    render.process(CommandMessage::Started(PathBuf::from("/b")))?;
+    render.process(CommandMessage::Started(PathBuf::from("/a")))?;
+    render.process(CommandMessage::Increment(output("/a", ExitState::Running)))?;
+    assert_eq!(vec!["/a started", "/a increment"], take());
+
+    render.process(CommandMessage::Final(Ok(output("/b", ExitState::Success))))?;
+    render.process(CommandMessage::Started(PathBuf::from("/c")))?;
+    assert!(take().is_empty());
+
+    render.process(CommandMessage::Final(Ok(output("/a", ExitState::Success))))?;
+    assert_eq!(
+        vec!["/a final", "/b started", "/b final", "/c started"],
+        take()
+    );
+    render.process(CommandMessage::Increment(output("/c", ExitState::Running)))?;
+    assert_eq!(vec!["/c increment"], take());
+
+    render.process(CommandMessage::Started(PathBuf::from("/d")))?;
+    render.finish()?;
+    assert_eq!(vec!["/d started"], take());
+    Ok(())
+}
+
+#[test]
+fn test_prefix() -> Result<()> {
+    let targets: Vec<Target> = ["/a", "/longer/path"]
+        .iter()
+        .map(|dir| Target {
+            dir: PathBuf::from(dir),
+            alias: None,
+            default_branch: None,
+            tags: vec![],
+            env: Default::default(),
+            index: 0,
+            total: 0,
+        })
+        .collect();
+    let render = PrefixRender::new(&targets)?;
+    assert_eq!(12, render.width);
+    let prefix = render.prefix(Path::new("/a"))?;
+    assert!(prefix.ends_with(&format!("{:12} |{}", "/a", style::Reset)));
+    Ok(())
+}
@@ -0,0 +1,288 @@
+use crate::cmds::git::current_branch;
+use crate::paths::expand;
+use anyhow::{bail, Context, Result};
+use globset::{Glob, GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use serde::Serialize;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A smart tag, applied to every directory meeting all of the rule's conditions.
+///
+/// Rules are evaluated when directories are selected, so they never need updating as
+/// repositories come and go.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Rule {
+    pub tag: String,
+    /// A glob the directory's path must match, such as `~/work/*`
+    ///
+    /// Directories matching this are selected even if they've never been tagged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Files which must all exist in the directory, such as `Cargo.toml`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contains: Vec<String>,
+    /// A glob which the URL of at least one git remote must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// A glob the currently checked out git branch must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+}
+
+/// The rules from the config file, ready for evaluating against directories.
+pub struct Rules<'a> {
+    rules: Vec<Compiled<'a>>,
+}
+
+struct Compiled<'a> {
+    rule: &'a Rule,
+    path: Option<(String, GlobMatcher)>,
+    remote: Option<GlobMatcher>,
+    branch: Option<GlobMatcher>,
+}
+
+impl<'a> Rules<'a> {
+    /// Compile the `rules`, with relative paths taken to be under `root`
+    pub fn new(rules: &'a [Rule], root: Option<&Path>) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                Compiled::new(rule, root)
+                    .with_context(|| format!("Invalid rule for tag {}", rule.tag))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Rules { rules })
+    }
+
+    /// Directories on disk matching the `path` of a rule, and all its other conditions
+    pub fn discover(&self) -> Vec<PathBuf> {
+        let mut found = Vec::new();
+        for compiled in &self.rules {
+            if let Some((pattern, _)) = &compiled.path {
+                found.extend(
+                    expand_glob(pattern)
+                        .into_iter()
+                        .filter(|dir| compiled.matches(dir, &mut Git::default())),
+                );
+            }
+        }
+        found
+    }
+
+    /// The tags given to `dir` by the rules
+    pub fn tags(&self, dir: &Path) -> Vec<&'a str> {
+        let mut git = Git::default();
+        self.rules
+            .iter()
+            .filter(|compiled| compiled.matches(dir, &mut git))
+            .map(|compiled| compiled.rule.tag.as_str())
+            .collect()
+    }
+}
+
+impl<'a> Compiled<'a> {
+    fn new(rule: &'a Rule, root: Option<&Path>) -> Result<Self> {
+        if rule.path.is_none()
+            && rule.contains.is_empty()
+            && rule.remote.is_none()
+            && rule.branch.is_none()
+        {
+            bail!("A rule needs at least one of path, contains, remote or branch");
+        }
+        let path = match &rule.path {
+            Some(pattern) => {
+                let pattern = expand(pattern, root)?.to_string_lossy().to_string();
+                if pattern.contains("**") {
+                    bail!("Rule paths can't use **: {}", pattern);
+                }
+                let glob = GlobBuilder::new(&pattern)
+                    .literal_separator(true)
+                    .build()?
+                    .compile_matcher();
+                Some((pattern, glob))
+            }
+            None => None,
+        };
+        let glob = |pattern: &Option<String>| -> Result<_> {
+            pattern
+                .as_ref()
+                .map(|p| Ok(Glob::new(p)?.compile_matcher()))
+                .transpose()
+        };
+        Ok(Compiled {
+            rule,
+            path,
+            remote: glob(&rule.remote)?,
+            branch: glob(&rule.branch)?,
+        })
+    }
+
+    fn matches(&self, dir: &Path, git: &mut Git) -> bool {
+        if let Some((_, glob)) = &self.path {
+            if !glob.is_match(dir) {
+                return false;
+            }
+        }
+        if !self
+            .rule
+            .contains
+            .iter()
+            .all(|file| dir.join(file).exists())
+        {
+            return false;
+        }
+        if let Some(remote) = &self.remote {
+            if !git.remotes(dir).iter().any(|url| remote.is_match(url)) {
+                return false;
+            }
+        }
+        if let Some(branch) = &self.branch {
+            if !git.branch(dir).is_some_and(|b| branch.is_match(b)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The git details of a single directory, looked up only if a rule needs them
+#[derive(Default)]
+struct Git {
+    details: Option<(Vec<String>, Option<String>)>,
+}
+
+impl Git {
+    fn remotes(&mut self, dir: &Path) -> &[String] {
+        &self.load(dir).0
+    }
+
+    fn branch(&mut self, dir: &Path) -> Option<&str> {
+        self.load(dir).1.as_deref()
+    }
+
+    fn load(&mut self, dir: &Path) -> &(Vec<String>, Option<String>) {
+        self.details.get_or_insert_with(|| {
+            let repo = match git2::Repository::open(dir) {
+                Ok(repo) => repo,
+                Err(_) => return (vec![], None),
+            };
+            let remotes = repo
+                .remotes()
+                .map(|names| {
+                    names
+                        .iter()
+                        .flatten()
+                        .filter_map(|name| repo.find_remote(name).ok())
+                        .filter_map(|remote| remote.url().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default();
+            (remotes, current_branch(&repo))
+        })
+    }
+}
+
+/// The directories matching an absolute glob, expanded one path component at a time
+///
+/// As in a shell, wildcards don't match names starting with a '.' unless the pattern does.
+fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let mut found = vec![PathBuf::from("/")];
+    for component in Path::new(pattern).components().skip(1) {
+        let part = component.as_os_str().to_string_lossy();
+        let glob = match Glob::new(&part) {
+            Ok(glob) if part.contains(['*', '?', '[', '{']) => glob.compile_matcher(),
+            _ => {
+                found = found
+                    .into_iter()
+                    .map(|dir| dir.join(&*part))
+                    .filter(|dir| dir.is_dir())
+                    .collect();
+                continue;
+            }
+        };
+        let mut next = Vec::new();
+        for dir in found {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let hidden = name.to_string_lossy().starts_with('.') && !part.starts_with('.');
+                if !hidden && glob.is_match(&name) && entry.path().is_dir() {
+                    next.push(entry.path());
+                }
+            }
+        }
+        next.sort();
+        found = next;
+    }
+    found
+}
+
+#[test]
+fn test_rules() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-rules-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let api = git2::Repository::init(root.join("work/api"))?;
+    api.set_head("refs/heads/main")?;
+    api.remote("origin", "git@github.com:example/api.git")?;
+    std::fs::write(root.join("work/api/Cargo.toml"), "")?;
+    git2::Repository::init(root.join("work/site"))?.set_head("refs/heads/main")?;
+    std::fs::write(root.join("work/site/package.json"), "")?;
+    std::fs::create_dir_all(root.join("work/.cache"))?;
+    std::fs::create_dir_all(root.join("home/blog"))?;
+    std::fs::write(root.join("home/blog/Cargo.toml"), "")?;
+
+    let rule = |tag: &str| Rule {
+        tag: tag.to_string(),
+        path: None,
+        contains: vec![],
+        remote: None,
+        branch: None,
+    };
+    let rules = vec![
+        Rule {
+            path: Some(format!("{}/work/*", root.display())),
+            ..rule("work")
+        },
+        Rule {
+            contains: vec![String::from("Cargo.toml")],
+            ..rule("rust")
+        },
+        Rule {
+            remote: Some(String::from("*github.com*")),
+            ..rule("github")
+        },
+        Rule {
+            branch: Some(String::from("main")),
+            ..rule("main")
+        },
+    ];
+    let rules = Rules::new(&rules, None)?;
+
+    assert_eq!(
+        vec![root.join("work/api"), root.join("work/site")],
+        rules.discover()
+    );
+    assert_eq!(
+        vec!["work", "rust", "github", "main"],
+        rules.tags(&root.join("work/api"))
+    );
+    assert_eq!(vec!["work", "main"], rules.tags(&root.join("work/site")));
+    assert_eq!(vec!["rust"], rules.tags(&root.join("home/blog")));
+
+    assert!(Rules::new(&[rule("empty")], None).is_err());
+    let relative = Rule {
+        path: Some(String::from("work/*")),
+        ..rule("work")
+    };
+    let relative = [relative];
+    assert!(Rules::new(&relative, None).is_err());
+    let rules = Rules::new(&relative, Some(&root))?;
+    assert_eq!(2, rules.discover().len());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Walks a directory tree looking for git repositories.
+pub struct Scanner {
+    /// How many directories deep below the root to look, the root itself being depth 0
+    pub max_depth: Option<usize>,
+    /// Look for repositories inside the repositories already found
+    pub nested: bool,
+    skip_names: GlobSet,
+    skip_paths: GlobSet,
+}
+
+impl Scanner {
+    /// A scanner skipping directories matching `.gitignore` style `skip` patterns.
+    ///
+    /// Patterns containing a '/' are matched against the path relative to the root being scanned,
+    /// and others against the directory name alone.
+    pub fn new(skip: &[String]) -> Result<Self> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in skip {
+            let trimmed = pattern.trim_end_matches('/');
+            let glob = |p: &str| {
+                GlobBuilder::new(p)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid skip pattern: {}", pattern))
+            };
+            if trimmed.contains('/') {
+                paths.add(glob(trimmed.trim_start_matches('/'))?);
+            } else {
+                names.add(glob(trimmed)?);
+            }
+        }
+        Ok(Scanner {
+            max_depth: None,
+            nested: false,
+            skip_names: names.build()?,
+            skip_paths: paths.build()?,
+        })
+    }
+
+    /// All the repositories at or below `root`, in the order they were found
+    pub fn scan(&self, root: &Path) -> Result<Vec<PathBuf>> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Couldn't scan {}", root.display()))?;
+        let mut found = Vec::new();
+        self.walk(&root, &root, 0, &mut found)?;
+        Ok(found)
+    }
+
+    fn walk(&self, root: &Path, dir: &Path, depth: usize, found: &mut Vec<PathBuf>) -> Result<()> {
+        if is_repository(dir) {
+            found.push(dir.to_path_buf());
+            if !self.nested {
+                return Ok(());
+            }
+        }
+        if self.max_depth.is_some_and(|max| depth >= max) {
+            return Ok(());
+        }
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            // Unreadable directories are skipped rather than failing the whole scan
+            Err(_) => return Ok(()),
+        };
+        let mut children = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            // Symlinks aren't followed so that loops can't happen
+            if !entry.file_type()?.is_dir() || entry.file_name() == ".git" {
+                continue;
+            }
+            let path = entry.path();
+            let relative = path.strip_prefix(root)?;
+            if self.skip_names.is_match(entry.file_name()) || self.skip_paths.is_match(relative) {
+                continue;
+            }
+            children.push(path);
+        }
+        children.sort();
+        for child in children {
+            self.walk(root, &child, depth + 1, found)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `dir` is the top of a git repository, worktree or bare repository
+fn is_repository(dir: &Path) -> bool {
+    let looks_like_repo =
+        dir.join(".git").exists() || (dir.join("HEAD").is_file() && dir.join("objects").is_dir());
+    looks_like_repo && git2::Repository::open(dir).is_ok()
+}
+
+#[test]
+fn test_scan() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("dirmux-scan-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let payments = git2::Repository::init(root.join("work/payments"))?;
+    let tree = payments.treebuilder(None)?.write()?;
+    let signature = git2::Signature::now("dirmux", "dirmux@example.com")?;
+    let tree = payments.find_tree(tree)?;
+    payments.commit(Some("HEAD"), &signature, &signature, "Initial", &tree, &[])?;
+    std::fs::create_dir_all(root.join("worktrees"))?;
+    payments.worktree("feature", &root.join("worktrees/feature"), None)?;
+    git2::Repository::init(root.join("work/payments/plugins/nested"))?;
+    git2::Repository::init_bare(root.join("mirrors/infra.git"))?;
+    git2::Repository::init(root.join("work/vendor/lib"))?;
+    git2::Repository::init(root.join("deep/er/still/repo"))?;
+    std::fs::create_dir_all(root.join("work/empty"))?;
+    let root = root.canonicalize()?;
+
+    let scanner = Scanner::new(&[String::from("vendor/"), String::from("deep/er")])?;
+    assert_eq!(
+        vec![
+            root.join("mirrors/infra.git"),
+            root.join("work/payments"),
+            root.join("worktrees/feature"),
+        ],
+        scanner.scan(&root)?
+    );
+
+    let mut scanner = Scanner::new(&[])?;
+    scanner.nested = true;
+    scanner.max_depth = Some(3);
+    assert_eq!(
+        vec![
+            root.join("mirrors/infra.git"),
+            root.join("work/payments"),
+            root.join("work/vendor/lib"),
+            root.join("worktrees/feature"),
+        ],
+        scanner.scan(&root)?
+    );
+
+    scanner.max_depth = None;
+    assert_eq!(6, scanner.scan(&root)?.len());
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
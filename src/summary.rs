@@ -0,0 +1,137 @@
+use crate::options::ExitCodeMode;
+use crate::renderers::cleanup_path;
+use crate::CommandMessage;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+/// Tracks which directories failed so dirmux can exit with a meaningful code.
+pub struct Summary {
+    mode: ExitCodeMode,
+    failures: usize,
+    first_failure: Option<i32>,
+    started: HashSet<PathBuf>,
+    finished: HashSet<PathBuf>,
+}
+
+impl Summary {
+    pub fn new(mode: ExitCodeMode) -> Self {
+        Summary {
+            mode,
+            failures: 0,
+            first_failure: None,
+            started: HashSet::new(),
+            finished: HashSet::new(),
+        }
+    }
+
+    /// Account for a message on its way to the renderer
+    pub fn record(&mut self, msg: &CommandMessage) {
+        let (dir, code) = match msg {
+            CommandMessage::Started(dir) => {
+                self.started.insert(dir.clone());
+                return;
+            }
+            CommandMessage::Final(Ok(output)) => (&output.dir, output.status.code()),
+            CommandMessage::Final(Err(failure)) => (&failure.dir, 1),
+            _ => return,
+        };
+        self.finished.insert(dir.clone());
+        if code != 0 {
+            self.failures += 1;
+            self.first_failure.get_or_insert(code);
+        }
+    }
+
+    /// The code dirmux should exit with
+    pub fn exit_code(&self) -> i32 {
+        match self.mode {
+            ExitCodeMode::Count => std::cmp::min(self.failures, 255) as i32,
+            ExitCodeMode::First => self.first_failure.unwrap_or(0),
+            ExitCodeMode::None => 0,
+        }
+    }
+
+    /// Describe how far through `dirs` an interrupted run got
+    pub fn interrupted(&self, dirs: &[PathBuf]) -> Result<String> {
+        let mut running = Vec::new();
+        let mut cancelled = Vec::new();
+        for dir in dirs {
+            if !self.started.contains(dir) {
+                cancelled.push(cleanup_path(dir)?);
+            } else if !self.finished.contains(dir) {
+                running.push(cleanup_path(dir)?);
+            }
+        }
+
+        let mut out = format!(
+            "Interrupted: {} completed, {} cancelled, {} still running\n",
+            self.finished.len(),
+            cancelled.len(),
+            running.len()
+        );
+        for dir in running {
+            out.push_str(&format!("  running:   {}\n", dir));
+        }
+        for dir in cancelled {
+            out.push_str(&format!("  cancelled: {}\n", dir));
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+fn finish(summary: &mut Summary, dir: &str, status: crate::ExitState) {
+    let dir = PathBuf::from(dir);
+    summary.record(&CommandMessage::Started(dir.clone()));
+    summary.record(&CommandMessage::Final(Ok(crate::CommandOutput {
+        dir,
+        output: vec![],
+        error: vec![],
+        status,
+        details: None,
+        duration: std::time::Duration::ZERO,
+    })));
+}
+
+#[test]
+fn test_exit_code() {
+    use crate::ExitState;
+    let mut summaries: Vec<Summary> =
+        [ExitCodeMode::Count, ExitCodeMode::First, ExitCodeMode::None]
+            .iter()
+            .map(|mode| Summary::new(*mode))
+            .collect();
+    for summary in summaries.iter_mut() {
+        assert_eq!(0, summary.exit_code());
+        finish(summary, "/a", ExitState::Success);
+        finish(summary, "/b", ExitState::Code(3));
+        finish(summary, "/c", ExitState::Signal(9));
+        summary.record(&CommandMessage::Final(Err(
+            crate::failure::DirFailure::new(PathBuf::from("/d"), anyhow::anyhow!("broken")),
+        )));
+    }
+    assert_eq!(3, summaries[0].exit_code());
+    assert_eq!(3, summaries[1].exit_code());
+    assert_eq!(0, summaries[2].exit_code());
+
+    let mut many = Summary::new(ExitCodeMode::Count);
+    for i in 0..300 {
+        finish(&mut many, &format!("/{}", i), ExitState::Code(1));
+    }
+    assert_eq!(255, many.exit_code());
+}
+
+#[test]
+fn test_interrupted() -> Result<()> {
+    use crate::ExitState;
+    let mut summary = Summary::new(ExitCodeMode::Count);
+    finish(&mut summary, "/a", ExitState::Success);
+    summary.record(&CommandMessage::Started(PathBuf::from("/b")));
+    let dirs: Vec<PathBuf> = ["/a", "/b", "/c"].iter().map(PathBuf::from).collect();
+    assert_eq!(
+        "Interrupted: 1 completed, 1 cancelled, 1 still running\n  running:   /b\n  cancelled: /c\n",
+        summary.interrupted(&dirs)?
+    );
+    Ok(())
+}
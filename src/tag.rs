@@ -1,32 +1,79 @@
+use crate::dirs::lock_file;
 use crate::dirs::write_file;
 use crate::dirs::FileFormat;
 use crate::options::TagSubcommands::*;
 use crate::options::*;
-use anyhow::Result;
+use crate::query::SEPARATOR;
+use crate::renderers::{cleanup_path, format_table};
+use crate::scan::Scanner;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
 use std::path::PathBuf;
 
-pub fn handle(tagopts: &TagOpts, filename: &PathBuf, file: &FileFormat) -> Result<()> {
+/// Apply a tag subcommand to the config file, holding its lock from reading to writing
+pub fn handle(tagopts: &TagOpts, filename: &Path) -> Result<()> {
     let dir = std::env::current_dir()?;
-    let new_file = match &tagopts.action {
-        Add(opts) => add(file, opts, &dir),
-        Remove(opts) => remove(file, opts, &dir),
-        Gc => gc(file),
-    }?;
-    write_file(&new_file, filename)?;
+    match &tagopts.action {
+        Add(opts) => update(filename, |file| add(file, opts, &dir)),
+        Remove(opts) => update(filename, |file| remove(file, opts, &dir)),
+        Gc => update(filename, gc),
+        Rename(opts) => update(filename, |file| rename(file, opts)),
+        Copy(opts) => update(filename, |file| copy(file, opts)),
+        Merge(opts) => update(filename, |file| merge(file, opts)),
+        Delete(opts) => update(filename, |file| delete(file, opts)),
+        Scan(opts) => scan(filename, opts, &dir),
+        List(opts) => inspect(filename, |file| list(file, opts)),
+        Show(opts) => inspect(filename, |file| show(file, opts)),
+        Which(opts) => inspect(filename, |file| which(file, opts, &dir)),
+    }
+}
+
+/// Modify the config file, holding its lock from reading to writing
+pub(crate) fn update<F>(filename: &Path, f: F) -> Result<()>
+where
+    F: FnOnce(&FileFormat) -> Result<FileFormat>,
+{
+    let _lock = lock_file(filename)?;
+    let file = crate::config::load(filename)?;
+    let new_file = f(&file)?;
+    if new_file != file {
+        write_file(&new_file, filename)?;
+    }
+    Ok(())
+}
+
+/// Print something about the config file
+pub(crate) fn inspect<F>(filename: &Path, f: F) -> Result<()>
+where
+    F: FnOnce(&FileFormat) -> Result<String>,
+{
+    let file = crate::config::load(filename)?;
+    print!("{}", f(&file)?);
     Ok(())
 }
 
-fn add(file: &FileFormat, opts: &TagAddOpts, cwd: &PathBuf) -> Result<FileFormat> {
+/// The absolute, canonical directory given to a tag command, defaulting to the current directory
+fn target(path: &Option<PathBuf>, cwd: &Path) -> PathBuf {
+    let dir = match path {
+        Some(path) => cwd.join(path),
+        None => cwd.to_path_buf(),
+    };
+    dir.canonicalize().unwrap_or(dir)
+}
+
+fn add(file: &FileFormat, opts: &TagAddOpts, cwd: &Path) -> Result<FileFormat> {
     let mut f = file.clone();
-    let dir = opts.path.clone().unwrap_or(cwd.to_owned());
-    f.add(opts.tag.clone(), &dir);
+    f.add(opts.tag.clone(), &target(&opts.path, cwd));
     Ok(f)
 }
 
-fn remove(file: &FileFormat, opts: &TagRemoveOpts, cwd: &PathBuf) -> Result<FileFormat> {
+fn remove(file: &FileFormat, opts: &TagRemoveOpts, cwd: &Path) -> Result<FileFormat> {
     let mut f = file.clone();
-    let dir = opts.path.clone().unwrap_or(cwd.to_owned());
-    f.remove(opts.tag.clone(), &dir);
+    f.remove(opts.tag.clone(), &target(&opts.path, cwd));
     Ok(f)
 }
 
@@ -36,6 +83,199 @@ fn gc(file: &FileFormat) -> Result<FileFormat> {
     Ok(f)
 }
 
+fn rename(file: &FileFormat, opts: &TagRenameOpts) -> Result<FileFormat> {
+    let mut f = file.clone();
+    f.rename_tag(&opts.old, &opts.new, opts.force)?;
+    Ok(f)
+}
+
+fn copy(file: &FileFormat, opts: &TagCopyOpts) -> Result<FileFormat> {
+    let mut f = file.clone();
+    f.copy_tag(&opts.src, &opts.dst, opts.force)?;
+    Ok(f)
+}
+
+fn merge(file: &FileFormat, opts: &TagMergeOpts) -> Result<FileFormat> {
+    let (into, sources) = match opts.args.as_slice() {
+        [sources @ .., keyword, into] if keyword == "into" && !sources.is_empty() => {
+            (into, sources)
+        }
+        _ => bail!("Expected: dirmux tag merge <tag>... into <new-tag>"),
+    };
+    let sources: Vec<&str> = sources.iter().map(String::as_str).collect();
+    let mut f = file.clone();
+    f.merge_tags(&sources, into, opts.force)?;
+    Ok(f)
+}
+
+fn delete(file: &FileFormat, opts: &TagDeleteOpts) -> Result<FileFormat> {
+    let mut f = file.clone();
+    f.delete_tag(&opts.tag)?;
+    Ok(f)
+}
+
+fn scan(filename: &Path, opts: &TagScanOpts, cwd: &Path) -> Result<()> {
+    let mut scanner = Scanner::new(&opts.skip)?;
+    scanner.max_depth = opts.max_depth;
+    scanner.nested = opts.nested;
+    let found = scanner.scan(&cwd.join(&opts.root))?;
+
+    let report = |file: &FileFormat| -> Result<()> {
+        for dir in &found {
+            let tagged = file
+                .directory(dir)
+                .is_some_and(|d| d.tags.contains(&opts.tag));
+            let state = if tagged { "already tagged" } else { "new" };
+            println!("{} ({})", cleanup_path(dir)?, state);
+        }
+        Ok(())
+    };
+
+    if opts.dry_run {
+        return inspect(filename, |file| {
+            report(file)?;
+            Ok(format!(
+                "Would tag {} repositories with {}\n",
+                found.len(),
+                opts.tag
+            ))
+        });
+    }
+    update(filename, |file| {
+        report(file)?;
+        let mut f = file.clone();
+        for dir in &found {
+            f.add(opts.tag.clone(), dir);
+        }
+        println!("Tagged {} repositories with {}", found.len(), opts.tag);
+        Ok(f)
+    })
+}
+
+/// Each tag and every parent of a tag, in tree order with the directories under them
+fn tag_tree(file: &FileFormat) -> BTreeMap<Vec<&str>, BTreeSet<&PathBuf>> {
+    let mut tree: BTreeMap<Vec<&str>, BTreeSet<&PathBuf>> = BTreeMap::new();
+    for (tag, dirs) in file.tags() {
+        let levels: Vec<&str> = tag.split(SEPARATOR).collect();
+        for depth in 1..=levels.len() {
+            tree.entry(levels[..depth].to_vec())
+                .or_default()
+                .extend(dirs.iter().copied());
+        }
+    }
+    tree
+}
+
+fn list(file: &FileFormat, opts: &TagListOpts) -> Result<String> {
+    let tree = tag_tree(file);
+    // Each tag is shown by its last level, indented beneath its parent
+    let nested = |levels: &Vec<&str>| {
+        format!(
+            "{}{}",
+            "  ".repeat(levels.len() - 1),
+            levels[levels.len() - 1]
+        )
+    };
+    let out = match opts.format {
+        ListFormat::Plain => tree
+            .iter()
+            .map(|(levels, dirs)| format!("{} {}\n", nested(levels), dirs.len()))
+            .collect(),
+        ListFormat::Table => {
+            let rows: Vec<Vec<String>> = tree
+                .iter()
+                .map(|(levels, dirs)| vec![nested(levels), dirs.len().to_string()])
+                .collect();
+            format_table(&["TAG", "DIRECTORIES"], &rows)
+        }
+        ListFormat::Json => {
+            let sep = SEPARATOR.to_string();
+            let tags: Vec<_> = tree
+                .iter()
+                .map(|(levels, dirs)| json!({"tag": levels.join(&sep), "directories": dirs.len()}))
+                .collect();
+            to_json(&tags)?
+        }
+    };
+    Ok(out)
+}
+
+/// What is at a tagged path
+fn path_state(path: &Path) -> &'static str {
+    if !path.is_dir() {
+        "missing"
+    } else if git2::Repository::open(path).is_ok() {
+        "git"
+    } else {
+        "dir"
+    }
+}
+
+fn show(file: &FileFormat, opts: &TagShowOpts) -> Result<String> {
+    let dirs = file.tagged(&opts.tag);
+    let out = match opts.format {
+        ListFormat::Plain => dirs
+            .iter()
+            .map(|dir| format!("{} {}\n", dir.display(), path_state(dir)))
+            .collect(),
+        ListFormat::Table => {
+            let mut rows = Vec::new();
+            for dir in &dirs {
+                let state = path_state(dir);
+                let exists = if state == "missing" { "no" } else { "yes" };
+                let git = if state == "git" { "yes" } else { "no" };
+                rows.push(vec![
+                    dir.display().to_string(),
+                    exists.to_string(),
+                    git.to_string(),
+                ]);
+            }
+            format_table(&["DIRECTORY", "EXISTS", "GIT"], &rows)
+        }
+        ListFormat::Json => {
+            let dirs: Vec<_> = dirs
+                .iter()
+                .map(|dir| {
+                    let state = path_state(dir);
+                    json!({"path": dir, "exists": state != "missing", "git": state == "git"})
+                })
+                .collect();
+            to_json(&dirs)?
+        }
+    };
+    Ok(out)
+}
+
+fn which(file: &FileFormat, opts: &TagWhichOpts, cwd: &Path) -> Result<String> {
+    let path = target(&opts.path, cwd);
+    let details = file.directory(&path).cloned().unwrap_or_default();
+    let out = match opts.format {
+        ListFormat::Plain => details
+            .tags
+            .iter()
+            .map(|tag| format!("{}\n", tag))
+            .collect(),
+        ListFormat::Table => {
+            let rows: Vec<Vec<&str>> = details.tags.iter().map(|tag| vec![tag.as_str()]).collect();
+            format_table(&["TAG"], &rows)
+        }
+        ListFormat::Json => {
+            let mut value = serde_json::to_value(&details)?;
+            value["path"] = json!(path);
+            value["tags"] = json!(details.tags);
+            value["enabled"] = json!(details.enabled);
+            to_json(&value)?
+        }
+    };
+    Ok(out)
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String> {
+    let mut out = serde_json::to_string_pretty(value)?;
+    out.push('\n');
+    Ok(out)
+}
+
 #[test]
 fn test_add() -> Result<()> {
     let opts = TagAddOpts {
@@ -102,3 +342,190 @@ fn test_gc() -> Result<()> {
     assert_eq!(expected, result);
     Ok(())
 }
+
+#[test]
+fn test_list() -> Result<()> {
+    let file = sample();
+
+    let opts = |format| TagListOpts { format };
+    assert_eq!("rust 1\nwork 2\n", list(&file, &opts(ListFormat::Plain))?);
+    assert_eq!(
+        "TAG   DIRECTORIES\nrust  1\nwork  2\n",
+        list(&file, &opts(ListFormat::Table))?
+    );
+    let json: serde_json::Value = serde_json::from_str(&list(&file, &opts(ListFormat::Json))?)?;
+    assert_eq!(
+        json!([{"tag": "rust", "directories": 1}, {"tag": "work", "directories": 2}]),
+        json
+    );
+    Ok(())
+}
+
+#[test]
+fn test_list_tree() -> Result<()> {
+    let mut file = sample();
+    file.rename_tag("work", "job", false)?;
+    file.rename_tag("job", "work/payments", false)?;
+    file.add(String::from("work/infra"), Path::new("/src/infra"));
+    file.add(String::from("work-notes"), Path::new("/src/notes"));
+
+    let opts = |format| TagListOpts { format };
+    assert_eq!(
+        "rust 1\nwork 2\n  infra 1\n  payments 2\nwork-notes 1\n",
+        list(&file, &opts(ListFormat::Plain))?
+    );
+    let json: serde_json::Value = serde_json::from_str(&list(&file, &opts(ListFormat::Json))?)?;
+    assert_eq!(json!("work/infra"), json[2]["tag"]);
+    Ok(())
+}
+
+#[test]
+fn test_which() -> Result<()> {
+    let mut file = FileFormat::blank();
+    file.add(String::from("work"), Path::new("/src/payments"));
+    file.add(String::from("rust"), Path::new("/src/payments"));
+
+    let opts = TagWhichOpts {
+        path: Some(PathBuf::from("payments")),
+        format: ListFormat::Plain,
+    };
+    assert_eq!("work\nrust\n", which(&file, &opts, Path::new("/src"))?);
+    assert_eq!("", which(&file, &opts, Path::new("/elsewhere"))?);
+    Ok(())
+}
+
+/// A file with `work` on two directories and `rust` on one of them
+#[cfg(test)]
+fn sample() -> FileFormat {
+    let mut file = FileFormat::blank();
+    file.add(String::from("work"), Path::new("/src/payments"));
+    file.add(String::from("work"), Path::new("/src/infra"));
+    file.add(String::from("rust"), Path::new("/src/payments"));
+    file
+}
+
+#[test]
+fn test_rename() -> Result<()> {
+    let opts = TagRenameOpts {
+        old: String::from("work"),
+        new: String::from("job"),
+        force: false,
+    };
+    let subsequent = rename(&sample(), &opts)?;
+
+    let mut expected = FileFormat::blank();
+    expected.add(String::from("job"), Path::new("/src/payments"));
+    expected.add(String::from("job"), Path::new("/src/infra"));
+    expected.add(String::from("rust"), Path::new("/src/payments"));
+    assert_eq!(expected, subsequent);
+
+    let missing = TagRenameOpts {
+        old: String::from("nope"),
+        ..opts
+    };
+    assert!(rename(&sample(), &missing).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_rename_clobber() -> Result<()> {
+    let mut opts = TagRenameOpts {
+        old: String::from("rust"),
+        new: String::from("work"),
+        force: false,
+    };
+    assert!(rename(&sample(), &opts).is_err());
+
+    opts.force = true;
+    let subsequent = rename(&sample(), &opts)?;
+    let mut expected = FileFormat::blank();
+    expected.add(String::from("work"), Path::new("/src/payments"));
+    assert_eq!(expected, subsequent);
+    Ok(())
+}
+
+#[test]
+fn test_rename_parent() -> Result<()> {
+    let mut file = FileFormat::blank();
+    file.add(String::from("work"), Path::new("/src/infra"));
+    file.add(String::from("work/payments"), Path::new("/src/payments"));
+    file.add(String::from("work/payments/api"), Path::new("/src/api"));
+    file.add(String::from("workshop"), Path::new("/src/shop"));
+
+    let opts = TagRenameOpts {
+        old: String::from("work"),
+        new: String::from("job"),
+        force: false,
+    };
+    let subsequent = rename(&file, &opts)?;
+    let mut expected = FileFormat::blank();
+    expected.add(String::from("job"), Path::new("/src/infra"));
+    expected.add(String::from("job/payments"), Path::new("/src/payments"));
+    expected.add(String::from("job/payments/api"), Path::new("/src/api"));
+    expected.add(String::from("workshop"), Path::new("/src/shop"));
+    assert_eq!(expected, subsequent);
+
+    let into_itself = TagRenameOpts {
+        new: String::from("work/old"),
+        ..opts
+    };
+    assert!(rename(&file, &into_itself).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_copy() -> Result<()> {
+    let mut opts = TagCopyOpts {
+        src: String::from("rust"),
+        dst: String::from("oss"),
+        force: false,
+    };
+    let subsequent = copy(&sample(), &opts)?;
+    let mut expected = sample();
+    expected.add(String::from("oss"), Path::new("/src/payments"));
+    assert_eq!(expected, subsequent);
+
+    opts.dst = String::from("work");
+    assert!(copy(&sample(), &opts).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_merge() -> Result<()> {
+    let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect();
+    let mut opts = TagMergeOpts {
+        args: args(&["work", "rust", "into", "all"]),
+        force: false,
+    };
+    let subsequent = merge(&sample(), &opts)?;
+    let mut expected = FileFormat::blank();
+    expected.add(String::from("all"), Path::new("/src/payments"));
+    expected.add(String::from("all"), Path::new("/src/infra"));
+    assert_eq!(expected, subsequent);
+
+    // Merging into one of the merged tags needs no force
+    opts.args = args(&["work", "rust", "into", "work"]);
+    let mut expected = FileFormat::blank();
+    expected.add(String::from("work"), Path::new("/src/payments"));
+    expected.add(String::from("work"), Path::new("/src/infra"));
+    assert_eq!(expected, merge(&sample(), &opts)?);
+
+    opts.args = args(&["rust", "into", "work"]);
+    assert!(merge(&sample(), &opts).is_err());
+    opts.args = args(&["rust", "work", "all"]);
+    assert!(merge(&sample(), &opts).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_delete() -> Result<()> {
+    let opts = TagDeleteOpts {
+        tag: String::from("work"),
+    };
+    let subsequent = delete(&sample(), &opts)?;
+    let mut expected = FileFormat::blank();
+    expected.add(String::from("rust"), Path::new("/src/payments"));
+    assert_eq!(expected, subsequent);
+    assert!(delete(&subsequent, &opts).is_err());
+    Ok(())
+}
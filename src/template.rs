@@ -0,0 +1,83 @@
+/// Replace `{name}` placeholders in `text` with the values given by `lookup`.
+///
+/// Braces around anything `lookup` doesn't know are left as they are, so that `find -exec {} ...`
+/// and awk programs pass through untouched, as are shell variables such as `${dir}`. Anything
+/// between `{{` and `}}` is never expanded, leaving Go and Jinja templates such as
+/// `docker inspect -f '{{.Name}}'` alone, except that doubling the braces of a known placeholder
+/// escapes it, so `{{name}}` gives a literal `{name}`.
+pub fn expand<F>(text: &str, mut lookup: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find('{') {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if let Some(inner) = tail.strip_prefix("{{") {
+            let escaped = inner
+                .find("}}")
+                .map(|end| &inner[..end])
+                .filter(|name| is_name(name) && lookup(name).is_some());
+            if let Some(name) = escaped {
+                out.push('{');
+                out.push_str(name);
+                out.push('}');
+                rest = &tail[name.len() + 4..];
+                continue;
+            }
+            let end = inner.find("}}").map_or(tail.len(), |end| end + 4);
+            out.push_str(&tail[..end]);
+            rest = &tail[end..];
+            continue;
+        }
+        let placeholder = Some(tail)
+            .filter(|_| !out.ends_with('$'))
+            .and_then(|tail| tail.strip_prefix('{'))
+            .and_then(|inner| inner.find('}').map(|end| &inner[..end]))
+            .filter(|name| is_name(name))
+            .and_then(|name| Some((name.len(), lookup(name)?)));
+        match placeholder {
+            Some((len, value)) => {
+                out.push_str(&value);
+                rest = &tail[len + 2..];
+            }
+            None => {
+                out.push('{');
+                rest = &tail[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn is_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[test]
+fn test_expand() {
+    let lookup = |name: &str| match name {
+        "name" => Some(String::from("payments")),
+        "index" => Some(String::from("3")),
+        _ => None,
+    };
+    assert_eq!(
+        "/backup/payments-3.tgz",
+        expand("/backup/{name}-{index}.tgz", lookup)
+    );
+    assert_eq!(
+        "docker inspect -f '{{.Name}}' payments",
+        expand("docker inspect -f '{{.Name}}' {name}", lookup)
+    );
+    assert_eq!(
+        "{name} payments {{ {name} }} {{unknown}}",
+        expand("{{name}} {name} {{ {name} }} {{unknown}}", lookup)
+    );
+    assert_eq!("{{name", expand("{{name", lookup));
+    assert_eq!("find -exec {} ;", expand("find -exec {} ;", lookup));
+    assert_eq!("{print $1}", expand("{print $1}", lookup));
+    assert_eq!("{unknown} }{", expand("{unknown} }{", lookup));
+    assert_eq!("${name}/payments", expand("${name}/{name}", lookup));
+}